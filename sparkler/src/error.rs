@@ -1,6 +1,9 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use thiserror::Error;
 
-use crate::firecracker::api;
+use crate::firecracker::api::{self, InstanceState};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -21,6 +24,38 @@ pub enum Error {
         error: nix::Error,
     },
 
+    #[error("microVM is {actual:?}, expected {expected:?}")]
+    UnexpectedState {
+        expected: InstanceState,
+        actual: InstanceState,
+    },
+
+    #[error("Firecracker API was not ready within {timeout:?}{}", describe_exit(.exit_status))]
+    StartupTimeout {
+        timeout: Duration,
+        /// Exit status of the jailer, if it exited before the API was ready
+        exit_status: Option<unshare::ExitStatus>,
+    },
+
+    #[error("invalid jailer configuration: {0}")]
+    InvalidJailerConfig(String),
+
+    #[error("invalid path inside jail: {}", .0.display())]
+    InvalidJailPath(PathBuf),
+
+    #[error("no free user IDs in {start}..{end}")]
+    NoFreeUsers { start: u32, end: u32 },
+
+    #[error("microVM {id} is not running: {reason}")]
+    NotRunning { id: String, reason: String },
+
     #[error("jailer error")]
     Jailer(unshare::Error),
 }
+
+fn describe_exit(exit_status: &Option<unshare::ExitStatus>) -> String {
+    match exit_status {
+        Some(status) => format!(" (jailer exited: {})", status),
+        None => String::new(),
+    }
+}
@@ -1,2 +1,13 @@
 pub mod api;
+pub mod balloon;
+pub mod config;
+pub mod console;
+pub mod daemon;
+pub mod gc;
 pub mod jailer;
+pub mod monitor;
+pub mod shutdown;
+pub mod snapshot;
+pub mod staging;
+pub mod vm;
+pub mod vsock;
@@ -1,21 +1,79 @@
 //! Client for the Firecracker HTTP API
 
-// TODO: client-side logging/tracing
-// TODO: Firecracker metrics, logger, mmds, maybe snapshots, maybe vsock
-
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use http::StatusCode;
+use http::{Method, StatusCode};
 use hyper::body::{Body, Buf};
 use hyperlocal::{UnixClientExt, UnixConnector, Uri};
 use thiserror::Error;
+use tokio::time;
+use tracing::{debug, field, warn, Instrument};
+
+pub use self::model::{
+    ActionType, Balloon, BalloonStats, BalloonStatsUpdate, BalloonUpdate, BootSource, CpuConfig,
+    CpuTemplate, CpuidLeafModifier, CpuidRegister, CpuidRegisterModifier, Drive, EntropyDevice,
+    InstanceInfo, InstanceState, LogLevel, Logger, MachineConfiguration,
+    MachineConfigurationUpdate, MemoryBackend, MemoryBackendType, Metrics, MmdsConfig, MmdsVersion,
+    NetworkInterface, PartialDrive, PartialNetworkInterface, RateLimiter, RegisterModifier,
+    SnapshotCreateParams, SnapshotLoadParams, SnapshotType, TokenBucket, VcpuFeatures, Vsock,
+};
+use self::model::{FirecrackerVersion, InstanceActionInfo, Vm, VmState};
+pub use self::version::{Feature, Version};
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod version;
+
+/// Default time to wait for a response to each request
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of times to retry idempotent requests that could not connect
+const DEFAULT_MAX_RETRIES: u32 = 3;
 
-use self::model::InstanceActionInfo;
-pub use self::model::{ActionType, BootSource, Drive, InstanceInfo, RateLimiter, TokenBucket};
+/// Default delay before the first retry. Later retries back off linearly.
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
 
+/// Initial delay between checks in [`Client::wait_ready`]
+const READY_MIN_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Longest delay between checks in [`Client::wait_ready`]
+const READY_MAX_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Firecracker API client.
+///
+/// Every request is traced in a `firecracker_request` span recording the method, path, response status, and latency. Requests that take
+/// longer than the timeout fail with [`Error::Timeout`]. `GET` and `PUT` requests are idempotent in the Firecracker API, so they are retried
+/// if they fail to connect to the socket, as happens while Firecracker is starting. Requests that time out are never retried, since the VMM
+/// may still act on them, and neither are actions or snapshots, which have side effects each time they are performed.
+///
+/// Before using a feature that not every Firecracker release has, the client checks the VMM's version (see [`Client::version`]) and fails
+/// with [`Error::Unsupported`] if the feature is missing.
+#[derive(Clone, derive_builder::Builder)]
 pub struct Client {
+    /// Path to the Firecracker API socket
+    #[builder(setter(into))]
     socket_path: PathBuf,
+
+    /// Time to wait for a response to each request
+    #[builder(default = "DEFAULT_TIMEOUT")]
+    timeout: Duration,
+
+    /// Number of times to retry idempotent requests that could not connect
+    #[builder(default = "DEFAULT_MAX_RETRIES")]
+    max_retries: u32,
+
+    /// Delay before the first retry. Each later retry waits this much longer than the previous one.
+    #[builder(default = "DEFAULT_RETRY_BACKOFF")]
+    retry_backoff: Duration,
+
+    #[builder(setter(skip), default = "hyper::Client::unix()")]
     inner: hyper::Client<UnixConnector, Body>,
+
+    /// Firecracker version, once detected. Shared between clones, since they talk to the same VMM.
+    #[builder(setter(skip), default)]
+    version: Arc<Mutex<Option<Version>>>,
 }
 
 #[derive(Debug, Error)]
@@ -34,76 +92,362 @@ pub enum Error {
 
     #[error("server error: {fault_message}")]
     Server { fault_message: String },
+
+    #[error(
+        "invalid resource ID {0:?}: IDs may only contain ASCII letters, digits, and underscores"
+    )]
+    InvalidId(String),
+
+    #[error("{method} {path} timed out after {timeout:?}")]
+    Timeout {
+        method: Method,
+        path: String,
+        timeout: Duration,
+    },
+
+    #[error("invalid Firecracker version {0:?}")]
+    InvalidVersion(String),
+
+    #[error("{feature} is unsupported by Firecracker v{version} (requires v{})", .feature.since())]
+    Unsupported { feature: Feature, version: Version },
 }
 
 impl Client {
+    /// Creates a client for the API socket at `socket_path`, with the default timeout and retry settings. Use [`ClientBuilder`] to change
+    /// them.
     pub fn new<P: Into<PathBuf>>(socket_path: P) -> Client {
-        let inner = hyper::Client::unix();
         Client {
             socket_path: socket_path.into(),
-            inner,
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            inner: hyper::Client::unix(),
+            version: Arc::default(),
         }
     }
 
+    /// Returns a client for the same socket that uses `timeout` instead. This is useful for individual slow calls, like creating a snapshot
+    /// of a VM with a lot of memory.
+    pub fn with_timeout(&self, timeout: Duration) -> Client {
+        Client {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a client for the same socket that never retries requests, for requests that must not be repeated or delayed.
+    pub fn without_retries(&self) -> Client {
+        Client {
+            max_retries: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Path to the Firecracker API socket
+    pub fn socket_path(&self) -> &std::path::Path {
+        &self.socket_path
+    }
+
+    /// Waits up to `timeout` for Firecracker to start serving its API. This waits for the API socket to be created, backing off from a few
+    /// milliseconds between checks, and then for `GET /` to succeed. Returns the instance information from the first successful response,
+    /// or [`Error::Timeout`] if the API did not become available in time.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<InstanceInfo, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = READY_MIN_BACKOFF;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(Error::Timeout {
+                    method: Method::GET,
+                    path: "/".into(),
+                    timeout,
+                });
+            }
+
+            if self.socket_path.exists() {
+                // Probe without retries, since this loop already retries
+                let probe = Client {
+                    timeout: remaining,
+                    max_retries: 0,
+                    ..self.clone()
+                };
+                match probe.instance_info().await {
+                    Ok(info) => return Ok(info),
+                    Err(error) => debug!("Firecracker API not ready yet: {}", error),
+                }
+            }
+
+            time::sleep(backoff.min(remaining)).await;
+            backoff = (backoff * 2).min(READY_MAX_BACKOFF);
+        }
+    }
+
+    /// Returns the version of Firecracker serving the API. The version is requested from `GET /version`, falling back to
+    /// [`InstanceInfo::vmm_version`] on releases that don't have that endpoint, and cached for later calls.
+    pub async fn version(&self) -> Result<Version, Error> {
+        if let Some(version) = *self.version.lock().unwrap() {
+            return Ok(version);
+        }
+
+        let version = match self.get::<FirecrackerVersion>("/version").await {
+            Ok(version) => version.firecracker_version,
+            Err(Error::Client { .. }) => self.instance_info().await?.vmm_version,
+            Err(error) => return Err(error),
+        };
+        let version = version.parse()?;
+        debug!("Detected Firecracker v{}", version);
+        *self.version.lock().unwrap() = Some(version);
+        Ok(version)
+    }
+
+    /// Checks that the Firecracker serving the API supports `feature`.
+    pub async fn require(&self, feature: Feature) -> Result<(), Error> {
+        self.version().await?.require(feature)
+    }
+
     /// Returns general information about an instance.
     pub async fn instance_info(&self) -> Result<InstanceInfo, Error> {
-        let request = self
-            .builder_for("/")
-            .method("GET")
-            .body(Body::default())
-            .expect("malformed request");
-        let response = self.inner.request(request).await?;
-        if response.status() == StatusCode::OK {
-            deserialize_json(response).await
-        } else {
-            Err(deserialize_error(response).await)
-        }
+        self.get("/").await
     }
 
     /// Creates new boot source if one does not already exist, otherwise updates it.
     /// Will fail if update is not possible. Pre-boot only.
     pub async fn set_boot_source(&self, source: &BootSource) -> Result<(), Error> {
-        let request = self
-            .builder_for("/boot-source")
-            .method("PUT")
-            .body(serialize_json(source))
-            .expect("malformed request");
-        let response = self.inner.request(request).await?;
-        if response.status() == StatusCode::NO_CONTENT {
-            Ok(())
-        } else {
-            Err(deserialize_error(response).await)
-        }
+        self.put("/boot-source", source).await
     }
 
     /// Creates new drive with ID specified by the specified drive ID.
     /// If a drive with the specified ID already exists, updates its state based on new input.
     /// Will fail if update is not possible.
     pub async fn set_drive(&self, drive: &Drive) -> Result<(), Error> {
-        // TODO: can the drive ID in the URL ever be different from the drive ID in the body?
-        let request = self
-            .builder_for(&format!("/drives/{}", drive.drive_id))
-            .method("PUT")
-            .body(serialize_json(drive))
-            .expect("malformed request");
-        let response = self.inner.request(request).await?;
-        if response.status() == StatusCode::NO_CONTENT {
-            Ok(())
-        } else {
-            Err(deserialize_error(response).await)
+        self.put(&resource_path("/drives", &drive.drive_id)?, drive)
+            .await
+    }
+
+    /// Updates the properties of a drive, such as swapping its backing file or changing its rate limiter. Post-boot only.
+    pub async fn patch_drive(&self, update: &PartialDrive) -> Result<(), Error> {
+        self.patch(&resource_path("/drives", &update.drive_id)?, update)
+            .await
+    }
+
+    /// Gets the machine configuration of the VM. When called before the PUT operation, it will return the default values for the vCPU count (=1),
+    /// memory size (=128 MiB). By default SMT is disabled and there is no CPU template.
+    pub async fn get_machine_config(&self) -> Result<MachineConfiguration, Error> {
+        self.get("/machine-config").await
+    }
+
+    /// Updates the virtual machine configuration, setting the number of vCPUs and memory size. Any fields that are not given revert to their
+    /// defaults. Pre-boot only.
+    pub async fn set_machine_config(&self, config: &MachineConfiguration) -> Result<(), Error> {
+        let version = self.version().await?;
+        if config.track_dirty_pages {
+            version.require(Feature::DiffSnapshots)?;
+        }
+        self.put("/machine-config", &machine_config_body(version, config))
+            .await
+    }
+
+    /// Partially updates the virtual machine configuration. Only the fields that are set in `update` are changed. Pre-boot only.
+    pub async fn patch_machine_config(
+        &self,
+        update: &MachineConfigurationUpdate,
+    ) -> Result<(), Error> {
+        let version = self.version().await?;
+        if update.track_dirty_pages.is_some() {
+            version.require(Feature::DiffSnapshots)?;
+        }
+        self.patch("/machine-config", &machine_config_body(version, update))
+            .await
+    }
+
+    /// Creates new network interface with ID specified by the interface ID in `interface`.
+    /// If an interface with the specified ID already exists, updates its state based on new input.
+    /// Will fail if update is not possible. Pre-boot only.
+    pub async fn set_network_interface(&self, interface: &NetworkInterface) -> Result<(), Error> {
+        self.put(
+            &resource_path("/network-interfaces", &interface.iface_id)?,
+            interface,
+        )
+        .await
+    }
+
+    /// Updates the rate limiters applied to a network interface. Post-boot only.
+    pub async fn patch_network_interface(
+        &self,
+        update: &PartialNetworkInterface,
+    ) -> Result<(), Error> {
+        self.patch(
+            &resource_path("/network-interfaces", &update.iface_id)?,
+            update,
+        )
+        .await
+    }
+
+    /// Creates an MMDS (Microvm Metadata Service) data store, replacing any existing contents.
+    pub async fn put_mmds<S: serde::Serialize>(&self, data: &S) -> Result<(), Error> {
+        self.put("/mmds", data).await
+    }
+
+    /// Updates the MMDS data store, merging `data` into the existing contents as a JSON merge patch.
+    pub async fn patch_mmds<S: serde::Serialize>(&self, data: &S) -> Result<(), Error> {
+        self.patch("/mmds", data).await
+    }
+
+    /// Gets the contents of the MMDS data store.
+    pub async fn get_mmds<D: serde::de::DeserializeOwned>(&self) -> Result<D, Error> {
+        self.get("/mmds").await
+    }
+
+    /// Configures MMDS: which network interfaces it is reachable from, the IPv4 address it listens on, and the version of the
+    /// MMDS protocol. Pre-boot only.
+    pub async fn set_mmds_config(&self, config: &MmdsConfig) -> Result<(), Error> {
+        if config.version == Some(MmdsVersion::V2) {
+            self.require(Feature::MmdsV2).await?;
+        }
+        self.put("/mmds/config", config).await
+    }
+
+    /// Creates a snapshot of the microVM state. The microVM should be in the `Paused` state.
+    pub async fn create_snapshot(&self, params: &SnapshotCreateParams) -> Result<(), Error> {
+        self.require(Feature::Snapshots).await?;
+        if params.snapshot_type == Some(SnapshotType::Diff) {
+            self.require(Feature::DiffSnapshots).await?;
         }
+        self.put("/snapshot/create", params).await
+    }
+
+    /// Loads the microVM state from a snapshot. Only accepted on a fresh Firecracker process (before configuring any resource other than the
+    /// logger and metrics).
+    pub async fn load_snapshot(&self, params: &SnapshotLoadParams) -> Result<(), Error> {
+        // Older releases took a `mem_file_path` instead of a memory backend
+        self.require(Feature::MemoryBackend).await?;
+        if params.enable_diff_snapshots {
+            self.require(Feature::DiffSnapshots).await?;
+        }
+        self.put("/snapshot/load", params).await
+    }
+
+    /// Pauses the microVM. Post-boot only.
+    pub async fn pause(&self) -> Result<(), Error> {
+        self.patch(
+            "/vm",
+            &Vm {
+                state: VmState::Paused,
+            },
+        )
+        .await
+    }
+
+    /// Resumes a paused microVM. Post-boot only.
+    pub async fn resume(&self) -> Result<(), Error> {
+        self.patch(
+            "/vm",
+            &Vm {
+                state: VmState::Resumed,
+            },
+        )
+        .await
+    }
+
+    /// Creates a new vsock device. If a vsock device already exists, it is replaced. Pre-boot only.
+    pub async fn set_vsock(&self, vsock: &Vsock) -> Result<(), Error> {
+        self.put("/vsock", vsock).await
+    }
+
+    /// Initializes the logger by specifying a named pipe or a file for the logs output. Pre-boot only.
+    pub async fn set_logger(&self, logger: &Logger) -> Result<(), Error> {
+        self.put("/logger", logger).await
+    }
+
+    /// Initializes the metrics system by specifying a named pipe or a file for the metrics output. Pre-boot only.
+    pub async fn set_metrics(&self, metrics: &Metrics) -> Result<(), Error> {
+        self.put("/metrics", metrics).await
+    }
+
+    /// Creates an entropy device, which gives the guest a source of randomness through virtio-rng. Pre-boot only.
+    pub async fn set_entropy_device(&self, device: &EntropyDevice) -> Result<(), Error> {
+        self.require(Feature::Entropy).await?;
+        self.put("/entropy", device).await
+    }
+
+    /// Sets a custom CPU template, which modifies the CPUID, MSRs, or registers that the guest sees. This allows a consistent CPU model
+    /// across hosts with different processors. Pre-boot only, and cannot be combined with a static
+    /// [`MachineConfiguration::cpu_template`].
+    pub async fn set_cpu_config(&self, config: &CpuConfig) -> Result<(), Error> {
+        self.require(Feature::CustomCpuTemplates).await?;
+        self.put("/cpu-config", config).await
+    }
+
+    /// Creates a balloon device. Pre-boot only.
+    pub async fn set_balloon(&self, balloon: &Balloon) -> Result<(), Error> {
+        self.require(Feature::Balloon).await?;
+        self.put("/balloon", balloon).await
+    }
+
+    /// Returns the current balloon device configuration.
+    pub async fn get_balloon(&self) -> Result<Balloon, Error> {
+        self.require(Feature::Balloon).await?;
+        self.get("/balloon").await
+    }
+
+    /// Updates the target size of the balloon. Post-boot only.
+    pub async fn patch_balloon(&self, update: &BalloonUpdate) -> Result<(), Error> {
+        self.require(Feature::Balloon).await?;
+        self.patch("/balloon", update).await
+    }
+
+    /// Returns the latest memory statistics reported by the balloon device. Statistics must have been enabled by setting
+    /// [`Balloon::stats_polling_interval_s`].
+    pub async fn balloon_statistics(&self) -> Result<BalloonStats, Error> {
+        self.require(Feature::Balloon).await?;
+        self.get("/balloon/statistics").await
+    }
+
+    /// Updates the balloon statistics polling interval. Post-boot only, and statistics must have been enabled before boot.
+    pub async fn patch_balloon_statistics(&self, update: &BalloonStatsUpdate) -> Result<(), Error> {
+        self.require(Feature::Balloon).await?;
+        self.patch("/balloon/statistics", update).await
     }
 
     /// Creates a synchronous (to the VMM) action.
     pub async fn action(&self, action: ActionType) -> Result<(), Error> {
-        let request = self
-            .builder_for("/actions")
-            .method("PUT")
-            .body(serialize_json(&InstanceActionInfo {
+        self.put(
+            "/actions",
+            &InstanceActionInfo {
                 action_type: action,
-            }))
-            .expect("malformed request");
-        let response = self.inner.request(request).await?;
+            },
+        )
+        .await
+    }
+
+    /// Sends a `GET` request, deserializing the response body.
+    async fn get<D: serde::de::DeserializeOwned>(&self, path: &str) -> Result<D, Error> {
+        let response = self.send(Method::GET, path, None).await?;
+        if response.status() == StatusCode::OK {
+            deserialize_json(response).await
+        } else {
+            Err(deserialize_error(response).await)
+        }
+    }
+
+    /// Sends a `PUT` request with a JSON body, expecting an empty response.
+    async fn put<S: serde::Serialize>(&self, path: &str, body: &S) -> Result<(), Error> {
+        self.send_no_content(Method::PUT, path, body).await
+    }
+
+    /// Sends a `PATCH` request with a JSON body, expecting an empty response.
+    async fn patch<S: serde::Serialize>(&self, path: &str, body: &S) -> Result<(), Error> {
+        self.send_no_content(Method::PATCH, path, body).await
+    }
+
+    async fn send_no_content<S: serde::Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &S,
+    ) -> Result<(), Error> {
+        let response = self.send(method, path, Some(serialize_json(body))).await?;
         if response.status() == StatusCode::NO_CONTENT {
             Ok(())
         } else {
@@ -111,6 +455,68 @@ impl Client {
         }
     }
 
+    /// Sends a request, applying the timeout and retrying idempotent requests on failure.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<hyper::Response<Body>, Error> {
+        let span = tracing::debug_span!(
+            "firecracker_request",
+            %method,
+            path,
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        let retryable = is_retryable(&method, path);
+
+        async move {
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                let request = self
+                    .builder_for(path)
+                    .method(method.clone())
+                    .body(body.clone().map(Body::from).unwrap_or_default())
+                    .expect("malformed request");
+
+                let start = Instant::now();
+                let result = match time::timeout(self.timeout, self.inner.request(request)).await {
+                    Ok(Ok(response)) => Ok(response),
+                    Ok(Err(error)) => Err(Error::Transport(error)),
+                    Err(_) => Err(Error::Timeout {
+                        method: method.clone(),
+                        path: path.to_string(),
+                        timeout: self.timeout,
+                    }),
+                };
+                let span = tracing::Span::current();
+                span.record("latency_ms", start.elapsed().as_millis() as u64);
+
+                match result {
+                    Ok(response) => {
+                        span.record("status", response.status().as_u16());
+                        debug!("Firecracker responded with {}", response.status());
+                        return Ok(response);
+                    }
+                    Err(Error::Transport(error))
+                        if retryable && error.is_connect() && attempt <= self.max_retries =>
+                    {
+                        warn!("Attempt {} failed, retrying: {}", attempt, error);
+                        time::sleep(self.retry_backoff * attempt).await;
+                    }
+                    Err(error) => {
+                        warn!("Request failed: {}", error);
+                        return Err(error);
+                    }
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
     fn builder_for(&self, path: &str) -> http::request::Builder {
         http::Request::builder()
             .uri(hyper::Uri::from(Uri::new(&self.socket_path, path)))
@@ -119,9 +525,39 @@ impl Client {
     }
 }
 
+/// Whether a request can be sent again if it could not connect. `GET` and `PUT` requests are idempotent, except for actions and snapshots.
+fn is_retryable(method: &Method, path: &str) -> bool {
+    (method == Method::GET || method == Method::PUT)
+        && path != "/actions"
+        && !path.starts_with("/snapshot/")
+}
+
+/// Builds the path for a resource identified by `id` under `collection`, such as `/drives/rootfs`. Since the ID is also sent in the request
+/// body, it's validated to ensure that it can't address a different resource than the one in the body.
+pub(crate) fn resource_path(collection: &str, id: &str) -> Result<String, Error> {
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(format!("{}/{}", collection, id))
+    } else {
+        Err(Error::InvalidId(id.to_string()))
+    }
+}
+
+/// Builds a `/machine-config` request body for Firecracker `version`, renaming `smt` to `ht_enabled` for releases before [`Feature::Smt`].
+fn machine_config_body<S: serde::Serialize>(version: Version, config: &S) -> serde_json::Value {
+    let mut body = serde_json::to_value(config).expect("malformed machine configuration");
+    if !version.supports(Feature::Smt) {
+        if let Some(fields) = body.as_object_mut() {
+            if let Some(smt) = fields.remove("smt") {
+                fields.insert("ht_enabled".into(), smt);
+            }
+        }
+    }
+    body
+}
+
 /// Serialize a value to a JSON body
-fn serialize_json<S: serde::Serialize>(body: &S) -> Body {
-    serde_json::to_vec(body).expect("malformed body").into()
+fn serialize_json<S: serde::Serialize>(body: &S) -> Vec<u8> {
+    serde_json::to_vec(body).expect("malformed body")
 }
 
 /// Deserializes the HTTP response body as JSON
@@ -153,18 +589,26 @@ async fn deserialize_error(response: hyper::Response<Body>) -> Error {
 
 /// Firecracker API model types, from the [spec](https://github.com/firecracker-microvm/firecracker/blob/master/src/api_server/swagger/firecracker.yaml).
 mod model {
+    use std::net::Ipv4Addr;
     use std::path::PathBuf;
 
     use serde::{Deserialize, Serialize};
 
-    #[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct Error {
         /// A description of the error condition
         pub fault_message: String,
     }
 
+    /// Version of the Firecracker process.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct FirecrackerVersion {
+        /// Firecracker build version.
+        pub firecracker_version: String,
+    }
+
     /// Describes MicroVM instance information.
-    #[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct InstanceInfo {
         /// Application name.
         pub app_name: String,
@@ -178,7 +622,7 @@ mod model {
     }
 
     /// Instance state, as part of the [`InstanceInfo`] response.
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub enum InstanceState {
         #[serde(rename = "Not started")]
         NotStarted,
@@ -188,13 +632,13 @@ mod model {
 
     /// Variant wrapper containing the real action.
     /// Used for the `/actions` endpoint.
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct InstanceActionInfo {
         pub action_type: ActionType,
     }
 
     /// Enumeration indicating what type of action is contained in the payload
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub enum ActionType {
         FlushMetrics,
         InstanceStart,
@@ -202,7 +646,7 @@ mod model {
     }
 
     /// Boot source descriptor.
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct BootSource {
         /// Kernel boot arguments
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -229,6 +673,363 @@ mod model {
         pub rate_limiter: Option<RateLimiter>,
     }
 
+    /// Describes the number of vCPUs, memory size, SMT capabilities and the CPU template.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct MachineConfiguration {
+        /// Number of vCPUs (either 1 or an even number, up to 32)
+        pub vcpu_count: u8,
+        /// Memory size of VM
+        pub mem_size_mib: u64,
+        /// Flag for enabling/disabling simultaneous multithreading. Can be enabled only on x86. Called `ht_enabled` before
+        /// [`Feature::Smt`](super::Feature::Smt).
+        #[serde(default, alias = "ht_enabled")]
+        pub smt: bool,
+        /// Enable dirty page tracking. If this is enabled, then incremental guest memory snapshots can be created. These belong to diff snapshots,
+        /// which contain, besides the microVM state, only the memory dirtied since a previous snapshot. Full snapshots each contain a full copy of
+        /// the guest memory. Requires [`Feature::DiffSnapshots`](super::Feature::DiffSnapshots) to be enabled, and is left out of requests when
+        /// disabled, since older releases reject it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub track_dirty_pages: bool,
+        /// CPU template to apply, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub cpu_template: Option<CpuTemplate>,
+    }
+
+    /// Partial update of a [`MachineConfiguration`]. Fields that are `None` are left unchanged.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+    pub struct MachineConfigurationUpdate {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub vcpu_count: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub mem_size_mib: Option<u64>,
+        #[serde(alias = "ht_enabled", skip_serializing_if = "Option::is_none")]
+        pub smt: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub track_dirty_pages: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cpu_template: Option<CpuTemplate>,
+    }
+
+    /// The CPU template defines a set of flags to be disabled from the microvm so that the features exposed to the guest are the same as in the
+    /// selected instance type. Templates other than `None` are only available on x86 (and `V1N1` on ARM).
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum CpuTemplate {
+        C3,
+        T2,
+        T2S,
+        T2CL,
+        T2A,
+        V1N1,
+        None,
+    }
+
+    /// Partial drive update. Only `path_on_host` and the rate limiter can be changed after boot.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct PartialDrive {
+        pub drive_id: String,
+        /// Host level path for the guest drive
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub path_on_host: Option<PathBuf>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub rate_limiter: Option<RateLimiter>,
+    }
+
+    /// Defines a network interface.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct NetworkInterface {
+        pub iface_id: String,
+        /// Host level path for the guest network interface
+        pub host_dev_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub guest_mac: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub rx_rate_limiter: Option<RateLimiter>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tx_rate_limiter: Option<RateLimiter>,
+    }
+
+    /// Defines a partial network interface structure, used to update the rate limiters for that interface, after microvm start.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct PartialNetworkInterface {
+        pub iface_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub rx_rate_limiter: Option<RateLimiter>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tx_rate_limiter: Option<RateLimiter>,
+    }
+
+    /// Defines the MMDS configuration.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct MmdsConfig {
+        /// Enumeration indicating the MMDS version to be configured.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub version: Option<MmdsVersion>,
+        /// List of the network interface IDs capable of forwarding packets to the MMDS. Network interface IDs mentioned must be valid at the
+        /// time of this request. The net device model will reply to HTTP GET requests sent to the MMDS address via the interfaces mentioned.
+        /// In this case, both ARP requests and TCP segments heading to `ipv4_address` are intercepted by the device model, and do not reach the
+        /// associated TAP device.
+        pub network_interfaces: Vec<String>,
+        /// A valid IPv4 link-local address. Defaults to `169.254.169.254`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ipv4_address: Option<Ipv4Addr>,
+    }
+
+    /// MMDS protocol version. V2 requires guests to obtain a session token before reading metadata.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum MmdsVersion {
+        V1,
+        V2,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct SnapshotCreateParams {
+        /// Path to the file that will contain the guest memory.
+        pub mem_file_path: PathBuf,
+        /// Path to the file that will contain the microVM state.
+        pub snapshot_path: PathBuf,
+        /// Type of snapshot to create. It is optional and by default, a full snapshot is created.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub snapshot_type: Option<SnapshotType>,
+        /// The microVM version for which we want to create the snapshot. It is optional and it defaults to the current version.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub version: Option<String>,
+    }
+
+    /// Type of snapshot to create.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum SnapshotType {
+        /// Contains the full guest memory
+        Full,
+        /// Contains only the guest memory dirtied since the previous snapshot. Requires dirty page tracking to be enabled in the
+        /// [`MachineConfiguration`].
+        Diff,
+    }
+
+    /// Defines the configuration used for handling snapshot resume.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct SnapshotLoadParams {
+        /// Enable support for incremental (diff) snapshots by tracking dirty guest pages.
+        #[serde(default)]
+        pub enable_diff_snapshots: bool,
+        /// Configuration for the backend that handles memory load.
+        pub mem_backend: MemoryBackend,
+        /// Path to the file that contains the microVM state to be loaded.
+        pub snapshot_path: PathBuf,
+        /// When set to true, the vm is also resumed if the snapshot load is successful.
+        #[serde(default)]
+        pub resume_vm: bool,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct MemoryBackend {
+        pub backend_type: MemoryBackendType,
+        /// Based on `backend_type` it is either 1) Path to the file that contains the guest memory to be loaded 2) Path to the UDS where a
+        /// process is listening for a UFFD initialization control payload and open file descriptor that it can use to serve this process's
+        /// guest memory page faults
+        pub backend_path: PathBuf,
+    }
+
+    /// Where guest memory is loaded from when restoring a snapshot.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum MemoryBackendType {
+        /// Memory is mapped from a file
+        File,
+        /// Page faults are served by an external process over userfaultfd
+        Uffd,
+    }
+
+    /// Defines the microVM running state. It is especially useful in the snapshotting context.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Vm {
+        pub state: VmState,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum VmState {
+        Paused,
+        Resumed,
+    }
+
+    /// Defines a vsock device, backed by a set of Unix Domain Sockets, on the host side. For host-initiated connections, Firecracker will be
+    /// listening on the Unix socket identified by the path `uds_path`. Firecracker will create this socket, bind and listen on it.
+    /// Host-initiated connections will be performed by connection to this socket and issuing a connection forwarding request to the desired
+    /// guest-side vsock port (i.e. `CONNECT 52\n`, to connect to port 52). For guest-initiated connections, Firecracker will expect host
+    /// software to be bound and listening on Unix sockets at `uds_path_<PORT>`. E.g. "/path/to/host_vsock.sock_52" for port number 52.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Vsock {
+        /// Guest Vsock CID
+        pub guest_cid: u32,
+        /// Path to UNIX domain socket, used to proxy vsock connections.
+        pub uds_path: PathBuf,
+        /// This parameter has been deprecated since Firecracker v1.0.0.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub vsock_id: Option<String>,
+    }
+
+    /// Describes the configuration option for the logging capability.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Logger {
+        /// Path to the named pipe or file for the human readable log output.
+        pub log_path: PathBuf,
+        /// Set the level. The possible values are case-insensitive.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub level: Option<LogLevel>,
+        /// Whether or not to output the level in the logs.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub show_level: Option<bool>,
+        /// Whether or not to include the file path and line number of the log's origin.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub show_log_origin: Option<bool>,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum LogLevel {
+        Error,
+        Warning,
+        Info,
+        Debug,
+    }
+
+    /// Describes the configuration option for the metrics capability.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Metrics {
+        /// Path to the named pipe or file where the JSON-formatted metrics are flushed.
+        pub metrics_path: PathBuf,
+    }
+
+    /// Balloon device descriptor.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Balloon {
+        /// Target balloon size in MiB.
+        pub amount_mib: u32,
+        /// Whether the balloon should deflate when the guest has memory pressure.
+        pub deflate_on_oom: bool,
+        /// Interval in seconds between refreshing statistics. A non-zero value will enable the statistics. Defaults to 0.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub stats_polling_interval_s: Option<u32>,
+    }
+
+    /// Balloon device descriptor, used to change the target size after boot.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct BalloonUpdate {
+        /// Target balloon size in MiB.
+        pub amount_mib: u32,
+    }
+
+    /// Describes the balloon device statistics.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct BalloonStats {
+        /// Target number of pages the device aims to hold.
+        pub target_pages: u64,
+        /// Actual number of pages the device is holding.
+        pub actual_pages: u64,
+        /// Target amount of memory (in MiB) the device aims to hold.
+        pub target_mib: u64,
+        /// Actual amount of memory (in MiB) the device is holding.
+        pub actual_mib: u64,
+        /// The amount of memory that has been swapped in (in bytes).
+        pub swap_in: Option<u64>,
+        /// The amount of memory that has been swapped out to disk (in bytes).
+        pub swap_out: Option<u64>,
+        /// The number of major page faults that have occurred.
+        pub major_faults: Option<u64>,
+        /// The number of minor page faults that have occurred.
+        pub minor_faults: Option<u64>,
+        /// The amount of memory not being used for any purpose (in bytes).
+        pub free_memory: Option<u64>,
+        /// The total amount of memory available (in bytes).
+        pub total_memory: Option<u64>,
+        /// An estimate of how much memory is available (in bytes) for starting new applications, without pushing the system to swap.
+        pub available_memory: Option<u64>,
+        /// The amount of memory, in bytes, that can be quickly reclaimed without additional I/O. Typically these pages are used for caching
+        /// files from disk.
+        pub disk_caches: Option<u64>,
+        /// The number of successful hugetlb page allocations in the guest.
+        pub hugetlb_allocations: Option<u64>,
+        /// The number of failed hugetlb page allocations in the guest.
+        pub hugetlb_failures: Option<u64>,
+    }
+
+    /// Update the statistics polling interval, with the first statistics update scheduled immediately. Statistics cannot be turned on/off
+    /// after boot.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct BalloonStatsUpdate {
+        /// Interval in seconds between refreshing statistics.
+        pub stats_polling_interval_s: u32,
+    }
+
+    /// Entropy device configuration.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+    pub struct EntropyDevice {
+        /// Limits the rate at which the guest can read entropy
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub rate_limiter: Option<RateLimiter>,
+    }
+
+    /// A custom CPU template. Which modifiers apply depends on the host architecture: CPUID and MSR modifiers on x86_64, and register and
+    /// vCPU feature modifiers on aarch64.
+    ///
+    /// Bitmaps are strings like `0b0000000000000000000000000000000x`, from the most significant bit to the least, where `0` and `1` set a bit
+    /// and `x` leaves it unchanged.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+    pub struct CpuConfig {
+        /// KVM capabilities to check for (like `"171"`) or to disable (like `"!171"`)
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub kvm_capabilities: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub cpuid_modifiers: Vec<CpuidLeafModifier>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub msr_modifiers: Vec<RegisterModifier>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub reg_modifiers: Vec<RegisterModifier>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub vcpu_features: Vec<VcpuFeatures>,
+    }
+
+    /// Modifies the registers returned by one CPUID leaf and subleaf.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct CpuidLeafModifier {
+        /// CPUID leaf, as a hex string like `0x1`
+        pub leaf: String,
+        /// CPUID subleaf, as a hex string like `0x0`
+        pub subleaf: String,
+        /// KVM CPUID entry flags
+        pub flags: u32,
+        pub modifiers: Vec<CpuidRegisterModifier>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct CpuidRegisterModifier {
+        pub register: CpuidRegister,
+        pub bitmap: String,
+    }
+
+    /// Output register of the CPUID instruction.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum CpuidRegister {
+        Eax,
+        Ebx,
+        Ecx,
+        Edx,
+    }
+
+    /// Modifies a model-specific register (x86_64) or system register (aarch64).
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct RegisterModifier {
+        /// Register address, as a hex string like `0x10a`
+        pub addr: String,
+        pub bitmap: String,
+    }
+
+    /// Modifies the features enabled for vCPUs on aarch64.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct VcpuFeatures {
+        /// Index of the `kvm_vcpu_init.features` word to modify
+        pub index: u32,
+        pub bitmap: String,
+    }
+
     /// Defines an IO rate limiter with independent bytes/s and ops/s limits.
     /// Limits are defined by configuring each of the _bandwidth_ and _ops_ token buckets.
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -262,3 +1063,204 @@ mod model {
         pub size: u64,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockServer;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_idempotent() {
+        // Find an unused socket path, and start a server there only after the first attempt has failed
+        let socket_path = MockServer::start_temp()
+            .unwrap()
+            .socket_path()
+            .to_path_buf();
+        let client = ClientBuilder::default()
+            .socket_path(&socket_path)
+            .retry_backoff(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let server = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            MockServer::start(socket_path).unwrap()
+        });
+
+        let info = client.instance_info().await.unwrap();
+        assert_eq!(info.state, InstanceState::NotStarted);
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready() {
+        let socket_path = MockServer::start_temp()
+            .unwrap()
+            .socket_path()
+            .to_path_buf();
+        let client = Client::new(&socket_path);
+
+        assert!(matches!(
+            client.wait_ready(Duration::from_millis(30)).await,
+            Err(Error::Timeout { .. })
+        ));
+
+        let server = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            MockServer::start(socket_path).unwrap()
+        });
+        let info = client.wait_ready(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(info.app_name, "Firecracker");
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_feature() {
+        let server = MockServer::start_temp().unwrap();
+        server.state().vmm_version = "0.23.0".into();
+        let client = server.client();
+
+        let error = client
+            .set_balloon(&Balloon {
+                amount_mib: 64,
+                deflate_on_oom: true,
+                stats_polling_interval_s: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Unsupported {
+                feature: Feature::Balloon,
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "the balloon device is unsupported by Firecracker v0.23.0 (requires v0.24.0)"
+        );
+
+        // The version is only detected once, and unsupported requests never reach the VMM
+        client.get_balloon().await.unwrap_err();
+        assert_eq!(
+            server.state().requests,
+            vec![(Method::GET, "/version".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_machine_config_version() {
+        let config = MachineConfiguration {
+            vcpu_count: 2,
+            mem_size_mib: 256,
+            smt: true,
+            track_dirty_pages: false,
+            cpu_template: None,
+        };
+        assert_eq!(
+            machine_config_body(Version::new(0, 23, 0), &config),
+            serde_json::json!({"vcpu_count": 2, "mem_size_mib": 256, "ht_enabled": true})
+        );
+        assert_eq!(
+            machine_config_body(Version::new(1, 0, 0), &config),
+            serde_json::json!({"vcpu_count": 2, "mem_size_mib": 256, "smt": true})
+        );
+
+        let server = MockServer::start_temp().unwrap();
+        server.state().vmm_version = "0.23.0".into();
+        let client = server.client();
+        client.set_machine_config(&config).await.unwrap();
+        assert_eq!(client.get_machine_config().await.unwrap(), config);
+        assert!(matches!(
+            client
+                .set_machine_config(&MachineConfiguration {
+                    track_dirty_pages: true,
+                    ..config
+                })
+                .await,
+            Err(Error::Unsupported {
+                feature: Feature::DiffSnapshots,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_patch() {
+        let socket_path = MockServer::start_temp()
+            .unwrap()
+            .socket_path()
+            .to_path_buf();
+        let client = ClientBuilder::default()
+            .socket_path(&socket_path)
+            .retry_backoff(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let server = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            MockServer::start(socket_path).unwrap()
+        });
+
+        assert!(matches!(
+            client.patch_mmds(&serde_json::json!({})).await,
+            Err(Error::Transport(_))
+        ));
+        assert!(matches!(
+            client.action(ActionType::FlushMetrics).await,
+            Err(Error::Transport(_))
+        ));
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_timeout() {
+        // A server that accepts connections but never responds
+        let socket_path = MockServer::start_temp()
+            .unwrap()
+            .socket_path()
+            .to_path_buf();
+        let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+        let connections = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok(Ok((stream, _))) =
+                time::timeout(Duration::from_millis(300), listener.accept()).await
+            {
+                streams.push(stream);
+            }
+            streams.len()
+        });
+
+        let client = ClientBuilder::default()
+            .socket_path(&socket_path)
+            .timeout(Duration::from_millis(50))
+            .retry_backoff(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            client.instance_info().await,
+            Err(Error::Timeout { .. })
+        ));
+        assert_eq!(connections.await.unwrap(), 1);
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_resource_path() {
+        assert_eq!(
+            resource_path("/drives", "rootfs").unwrap(),
+            "/drives/rootfs"
+        );
+        assert_eq!(
+            resource_path("/network-interfaces", "eth_0").unwrap(),
+            "/network-interfaces/eth_0"
+        );
+
+        for id in &["", "../actions", "root fs", "rootfs?x=1", "data/1"] {
+            match resource_path("/drives", id) {
+                Err(Error::InvalidId(invalid)) => assert_eq!(&invalid, id),
+                other => panic!("expected {:?} to be rejected, got {:?}", id, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,651 @@
+//! In-process fake of the Firecracker API, for testing without KVM
+//!
+//! [`MockServer`] listens on a Unix socket and implements the endpoints used by [`Client`](super::Client), keeping track of the configured
+//! resources and whether the microVM has been started. Requests that Firecracker would reject, such as changing the boot source after
+//! `InstanceStart`, fail with a `fault_message` like the real one. No microVM is actually run.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use http::{Method, StatusCode};
+use hyper::body::{Body, Buf};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Request, Response, Server};
+use hyperlocal::UnixServerExt;
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use super::model::*;
+use super::Client;
+
+/// Used to generate unique socket paths for [`MockServer::start_temp`]
+static NEXT_SOCKET_ID: AtomicUsize = AtomicUsize::new(0);
+
+const PRE_BOOT_ONLY: &str = "The requested operation is not supported after starting the microVM.";
+const POST_BOOT_ONLY: &str =
+    "The requested operation is not supported before starting the microVM.";
+
+/// Resources configured on a [`MockServer`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockState {
+    /// Instance ID reported by `GET /`
+    pub id: String,
+    pub instance_state: InstanceState,
+    /// Firecracker version reported by `GET /` and `GET /version`
+    pub vmm_version: String,
+    pub boot_source: Option<BootSource>,
+    pub machine_config: MachineConfiguration,
+    pub drives: BTreeMap<String, Drive>,
+    pub network_interfaces: BTreeMap<String, NetworkInterface>,
+    pub mmds: Option<Value>,
+    pub mmds_config: Option<MmdsConfig>,
+    pub vsock: Option<Vsock>,
+    pub logger: Option<Logger>,
+    pub metrics: Option<Metrics>,
+    pub balloon: Option<Balloon>,
+    pub entropy: Option<EntropyDevice>,
+    pub cpu_config: Option<CpuConfig>,
+    /// Actions performed, in order
+    pub actions: Vec<ActionType>,
+    /// Snapshots created, in order
+    pub snapshots: Vec<SnapshotCreateParams>,
+    /// Every request received, as the method and path
+    pub requests: Vec<(Method, String)>,
+}
+
+impl Default for MockState {
+    fn default() -> MockState {
+        MockState {
+            id: "anonymous-instance".into(),
+            instance_state: InstanceState::NotStarted,
+            vmm_version: "1.4.0".into(),
+            boot_source: None,
+            machine_config: MachineConfiguration {
+                vcpu_count: 1,
+                mem_size_mib: 128,
+                smt: false,
+                track_dirty_pages: false,
+                cpu_template: None,
+            },
+            drives: BTreeMap::new(),
+            network_interfaces: BTreeMap::new(),
+            mmds: None,
+            mmds_config: None,
+            vsock: None,
+            logger: None,
+            metrics: None,
+            balloon: None,
+            entropy: None,
+            cpu_config: None,
+            actions: Vec::new(),
+            snapshots: Vec::new(),
+            requests: Vec::new(),
+        }
+    }
+}
+
+/// A fake Firecracker API server. The server shuts down and removes its socket when dropped.
+pub struct MockServer {
+    socket_path: PathBuf,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Starts a server listening on `socket_path`. Must be called from within a Tokio runtime.
+    pub fn start<P: Into<PathBuf>>(socket_path: P) -> io::Result<MockServer> {
+        let socket_path = socket_path.into();
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, request).await) }
+                }))
+            }
+        });
+
+        let server = Server::bind_unix(&socket_path)?
+            .serve(make_service)
+            .with_graceful_shutdown(async {
+                let _ = shutdown_rx.await;
+            });
+        tokio::spawn(server);
+
+        Ok(MockServer {
+            socket_path,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// Starts a server listening on a new socket in the system temporary directory.
+    pub fn start_temp() -> io::Result<MockServer> {
+        let socket_path = std::env::temp_dir().join(format!(
+            "sparkler-mock-{}-{}.socket",
+            std::process::id(),
+            NEXT_SOCKET_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        MockServer::start(socket_path)
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Creates a client connected to this server.
+    pub fn client(&self) -> Client {
+        Client::new(&self.socket_path)
+    }
+
+    /// Locks the server state, for inspection or to set up a scenario.
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Result of handling a request: the response body on success (if any), or a status and fault message.
+type Outcome = Result<Option<Value>, (StatusCode, String)>;
+
+async fn handle(state: &Mutex<MockState>, request: Request<Body>) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let body = match hyper::body::aggregate(request).await {
+        Ok(body) => body.reader(),
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    let body: Value = if method == Method::GET {
+        Value::Null
+    } else {
+        match serde_json::from_reader(body) {
+            Ok(body) => body,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, err.to_string()),
+        }
+    };
+
+    let mut state = state.lock().unwrap();
+    state.requests.push((method.clone(), path.clone()));
+    match route(&mut state, &method, &path, body) {
+        Ok(Some(body)) => Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&body).unwrap().into())
+            .unwrap(),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+        Err((status, fault_message)) => error_response(status, fault_message),
+    }
+}
+
+fn error_response(status: StatusCode, fault_message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&Error { fault_message }).unwrap().into())
+        .unwrap()
+}
+
+fn route(state: &mut MockState, method: &Method, path: &str, body: Value) -> Outcome {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::GET, [""]) => reply(&InstanceInfo {
+            app_name: "Firecracker".into(),
+            id: state.id.clone(),
+            state: state.instance_state,
+            vmm_version: state.vmm_version.clone(),
+        }),
+        (&Method::GET, ["version"]) => reply(&FirecrackerVersion {
+            firecracker_version: state.vmm_version.clone(),
+        }),
+        (&Method::PUT, ["actions"]) => action(state, parse(body)?),
+        (&Method::PUT, ["boot-source"]) => {
+            pre_boot(state)?;
+            state.boot_source = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::PUT, ["drives", id]) => {
+            pre_boot(state)?;
+            let drive: Drive = parse(body)?;
+            matching_id(id, &drive.drive_id)?;
+            state.drives.insert(drive.drive_id.clone(), drive);
+            Ok(None)
+        }
+        (&Method::PATCH, ["drives", id]) => {
+            post_boot(state)?;
+            let update: PartialDrive = parse(body)?;
+            matching_id(id, &update.drive_id)?;
+            let drive = state
+                .drives
+                .get_mut(*id)
+                .ok_or_else(|| bad_request(format!("Invalid block device ID: {}", id)))?;
+            if let Some(path_on_host) = update.path_on_host {
+                drive.path_on_host = path_on_host;
+            }
+            if let Some(rate_limiter) = update.rate_limiter {
+                drive.rate_limiter = Some(rate_limiter);
+            }
+            Ok(None)
+        }
+        (&Method::GET, ["machine-config"]) => reply(&state.machine_config),
+        (&Method::PUT, ["machine-config"]) => {
+            pre_boot(state)?;
+            state.machine_config = valid_machine_config(parse(body)?)?;
+            Ok(None)
+        }
+        (&Method::PATCH, ["machine-config"]) => {
+            pre_boot(state)?;
+            let update: MachineConfigurationUpdate = parse(body)?;
+            let mut config = state.machine_config.clone();
+            config.vcpu_count = update.vcpu_count.unwrap_or(config.vcpu_count);
+            config.mem_size_mib = update.mem_size_mib.unwrap_or(config.mem_size_mib);
+            config.smt = update.smt.unwrap_or(config.smt);
+            config.track_dirty_pages = update.track_dirty_pages.unwrap_or(config.track_dirty_pages);
+            config.cpu_template = update.cpu_template.or(config.cpu_template);
+            state.machine_config = valid_machine_config(config)?;
+            Ok(None)
+        }
+        (&Method::PUT, ["network-interfaces", id]) => {
+            pre_boot(state)?;
+            let interface: NetworkInterface = parse(body)?;
+            matching_id(id, &interface.iface_id)?;
+            state
+                .network_interfaces
+                .insert(interface.iface_id.clone(), interface);
+            Ok(None)
+        }
+        (&Method::PATCH, ["network-interfaces", id]) => {
+            post_boot(state)?;
+            let update: PartialNetworkInterface = parse(body)?;
+            matching_id(id, &update.iface_id)?;
+            let interface = state
+                .network_interfaces
+                .get_mut(*id)
+                .ok_or_else(|| bad_request(format!("Invalid network interface ID: {}", id)))?;
+            if let Some(rx) = update.rx_rate_limiter {
+                interface.rx_rate_limiter = Some(rx);
+            }
+            if let Some(tx) = update.tx_rate_limiter {
+                interface.tx_rate_limiter = Some(tx);
+            }
+            Ok(None)
+        }
+        (&Method::GET, ["mmds"]) => match &state.mmds {
+            Some(data) => Ok(Some(data.clone())),
+            None => Err((
+                StatusCode::NOT_FOUND,
+                "The MMDS data store is not initialized.".into(),
+            )),
+        },
+        (&Method::PUT, ["mmds"]) => {
+            state.mmds = Some(body);
+            Ok(None)
+        }
+        (&Method::PATCH, ["mmds"]) => {
+            let data = state
+                .mmds
+                .as_mut()
+                .ok_or_else(|| bad_request("The MMDS data store is not initialized.".into()))?;
+            merge_patch(data, body);
+            Ok(None)
+        }
+        (&Method::PUT, ["mmds", "config"]) => {
+            pre_boot(state)?;
+            let config: MmdsConfig = parse(body)?;
+            for iface_id in &config.network_interfaces {
+                if !state.network_interfaces.contains_key(iface_id) {
+                    return Err(bad_request(format!(
+                        "The list of network interface IDs provided contains at least one ID that does not correspond to any existing network interface: {}",
+                        iface_id
+                    )));
+                }
+            }
+            state.mmds_config = Some(config);
+            Ok(None)
+        }
+        (&Method::PUT, ["vsock"]) => {
+            pre_boot(state)?;
+            state.vsock = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::PUT, ["logger"]) => {
+            pre_boot(state)?;
+            state.logger = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::PUT, ["metrics"]) => {
+            pre_boot(state)?;
+            state.metrics = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::PUT, ["entropy"]) => {
+            pre_boot(state)?;
+            state.entropy = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::PUT, ["cpu-config"]) => {
+            pre_boot(state)?;
+            if state.machine_config.cpu_template.is_some() {
+                return Err(bad_request(
+                    "A custom CPU template cannot be combined with a static CPU template.".into(),
+                ));
+            }
+            state.cpu_config = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::GET, ["balloon"]) => match &state.balloon {
+            Some(balloon) => reply(balloon),
+            None => Err(bad_request("No balloon device found.".into())),
+        },
+        (&Method::PUT, ["balloon"]) => {
+            pre_boot(state)?;
+            state.balloon = Some(parse(body)?);
+            Ok(None)
+        }
+        (&Method::PATCH, ["balloon"]) => {
+            post_boot(state)?;
+            let update: BalloonUpdate = parse(body)?;
+            let balloon = balloon(state)?;
+            balloon.amount_mib = update.amount_mib;
+            Ok(None)
+        }
+        (&Method::GET, ["balloon", "statistics"]) => {
+            let mem_size_mib = state.machine_config.mem_size_mib;
+            let balloon = balloon(state)?;
+            if balloon.stats_polling_interval_s.unwrap_or(0) == 0 {
+                return Err(bad_request("Statistics are not enabled.".into()));
+            }
+            let pages = u64::from(balloon.amount_mib) * 256;
+            reply(&BalloonStats {
+                target_pages: pages,
+                actual_pages: pages,
+                target_mib: balloon.amount_mib.into(),
+                actual_mib: balloon.amount_mib.into(),
+                swap_in: None,
+                swap_out: None,
+                major_faults: None,
+                minor_faults: None,
+                free_memory: None,
+                total_memory: Some(mem_size_mib * 1024 * 1024),
+                available_memory: None,
+                disk_caches: None,
+                hugetlb_allocations: None,
+                hugetlb_failures: None,
+            })
+        }
+        (&Method::PATCH, ["balloon", "statistics"]) => {
+            post_boot(state)?;
+            let update: BalloonStatsUpdate = parse(body)?;
+            let balloon = balloon(state)?;
+            if balloon.stats_polling_interval_s.unwrap_or(0) == 0 {
+                return Err(bad_request(
+                    "Cannot enable/disable balloon statistics after boot.".into(),
+                ));
+            }
+            balloon.stats_polling_interval_s = Some(update.stats_polling_interval_s);
+            Ok(None)
+        }
+        (&Method::PATCH, ["vm"]) => {
+            post_boot(state)?;
+            let vm: Vm = parse(body)?;
+            state.instance_state = match vm.state {
+                VmState::Paused => InstanceState::Paused,
+                VmState::Resumed => InstanceState::Running,
+            };
+            Ok(None)
+        }
+        (&Method::PUT, ["snapshot", "create"]) => {
+            if state.instance_state != InstanceState::Paused {
+                return Err(bad_request(
+                    "Create snapshot error: the microVM must be paused.".into(),
+                ));
+            }
+            let params: SnapshotCreateParams = parse(body)?;
+            if params.snapshot_type == Some(SnapshotType::Diff)
+                && !state.machine_config.track_dirty_pages
+            {
+                return Err(bad_request(
+                    "Diff snapshots are not allowed on uVMs with dirty page tracking disabled."
+                        .into(),
+                ));
+            }
+            state.snapshots.push(params);
+            Ok(None)
+        }
+        (&Method::PUT, ["snapshot", "load"]) => {
+            pre_boot(state)?;
+            if state.boot_source.is_some() || !state.drives.is_empty() {
+                return Err(bad_request(
+                    "Loading a microVM snapshot not allowed after configuring boot-specific resources."
+                        .into(),
+                ));
+            }
+            let params: SnapshotLoadParams = parse(body)?;
+            state.instance_state = if params.resume_vm {
+                InstanceState::Running
+            } else {
+                InstanceState::Paused
+            };
+            Ok(None)
+        }
+        _ => Err(bad_request(format!(
+            "Invalid request method and/or path: {} {}",
+            method, path
+        ))),
+    }
+}
+
+fn action(state: &mut MockState, info: InstanceActionInfo) -> Outcome {
+    match info.action_type {
+        ActionType::InstanceStart => {
+            pre_boot(state)?;
+            if state.boot_source.is_none() {
+                return Err(bad_request(
+                    "Cannot start microvm without kernel configuration.".into(),
+                ));
+            }
+            state.instance_state = InstanceState::Running;
+        }
+        ActionType::FlushMetrics => {
+            if state.metrics.is_none() {
+                return Err(bad_request("The metrics system is not initialized.".into()));
+            }
+        }
+        ActionType::SendCtrlAltDel => post_boot(state)?,
+    }
+    state.actions.push(info.action_type);
+    Ok(None)
+}
+
+fn reply<S: serde::Serialize>(value: &S) -> Outcome {
+    Ok(Some(serde_json::to_value(value).unwrap()))
+}
+
+fn parse<D: serde::de::DeserializeOwned>(body: Value) -> Result<D, (StatusCode, String)> {
+    serde_json::from_value(body).map_err(|err| bad_request(err.to_string()))
+}
+
+fn bad_request(fault_message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, fault_message)
+}
+
+fn pre_boot(state: &MockState) -> Result<(), (StatusCode, String)> {
+    if state.instance_state == InstanceState::NotStarted {
+        Ok(())
+    } else {
+        Err(bad_request(PRE_BOOT_ONLY.into()))
+    }
+}
+
+fn post_boot(state: &MockState) -> Result<(), (StatusCode, String)> {
+    if state.instance_state == InstanceState::NotStarted {
+        Err(bad_request(POST_BOOT_ONLY.into()))
+    } else {
+        Ok(())
+    }
+}
+
+fn matching_id(path_id: &str, body_id: &str) -> Result<(), (StatusCode, String)> {
+    if path_id == body_id {
+        Ok(())
+    } else {
+        Err(bad_request(
+            "The id from the path does not match the id from the body!".into(),
+        ))
+    }
+}
+
+fn valid_machine_config(
+    config: MachineConfiguration,
+) -> Result<MachineConfiguration, (StatusCode, String)> {
+    if config.vcpu_count == 0
+        || config.vcpu_count > 32
+        || (config.vcpu_count > 1 && config.smt && config.vcpu_count % 2 == 1)
+    {
+        return Err(bad_request(
+            "The vCPU number is invalid! The vCPU number can only be 1 or an even number when SMT is enabled.".into(),
+        ));
+    }
+    if config.mem_size_mib == 0 {
+        return Err(bad_request("The memory size (MiB) is invalid.".into()));
+    }
+    Ok(config)
+}
+
+fn balloon(state: &mut MockState) -> Result<&mut Balloon, (StatusCode, String)> {
+    state
+        .balloon
+        .as_mut()
+        .ok_or_else(|| bad_request("No balloon device found.".into()))
+}
+
+/// Applies a JSON merge patch ([RFC 7396](https://tools.ietf.org/html/rfc7396)), as Firecracker does for `PATCH /mmds`.
+fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    merge_patch(target.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::Error;
+    use super::*;
+
+    fn boot_source() -> BootSource {
+        BootSource {
+            kernel_image_path: "vmlinux.bin".into(),
+            initrd_path: None,
+            boot_args: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_boot() {
+        let server = MockServer::start_temp().unwrap();
+        let client = server.client();
+
+        let info = client.instance_info().await.unwrap();
+        assert_eq!(info.state, InstanceState::NotStarted);
+
+        client.set_boot_source(&boot_source()).await.unwrap();
+        client
+            .patch_machine_config(&MachineConfigurationUpdate {
+                vcpu_count: Some(2),
+                mem_size_mib: Some(1024),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        client.action(ActionType::InstanceStart).await.unwrap();
+
+        let info = client.instance_info().await.unwrap();
+        assert_eq!(info.state, InstanceState::Running);
+        let machine_config = client.get_machine_config().await.unwrap();
+        assert_eq!(machine_config.vcpu_count, 2);
+        assert_eq!(machine_config.mem_size_mib, 1024);
+        assert_eq!(server.state().actions, vec![ActionType::InstanceStart]);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_errors() {
+        let server = MockServer::start_temp().unwrap();
+        let client = server.client();
+
+        match client.action(ActionType::InstanceStart).await {
+            Err(Error::Client { fault_message }) => {
+                assert_eq!(
+                    fault_message,
+                    "Cannot start microvm without kernel configuration."
+                )
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        match client.pause().await {
+            Err(Error::Client { fault_message }) => assert_eq!(fault_message, POST_BOOT_ONLY),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        client.set_boot_source(&boot_source()).await.unwrap();
+        client.action(ActionType::InstanceStart).await.unwrap();
+
+        match client.set_boot_source(&boot_source()).await {
+            Err(Error::Client { fault_message }) => assert_eq!(fault_message, PRE_BOOT_ONLY),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mmds() {
+        let server = MockServer::start_temp().unwrap();
+        let client = server.client();
+
+        client
+            .put_mmds(&json!({"hostname": "db-1", "peers": ["db-2"]}))
+            .await
+            .unwrap();
+        client
+            .patch_mmds(&json!({"peers": ["db-2", "db-3"], "secret": "hunter2"}))
+            .await
+            .unwrap();
+
+        let data: Value = client.get_mmds().await.unwrap();
+        assert_eq!(
+            data,
+            json!({"hostname": "db-1", "peers": ["db-2", "db-3"], "secret": "hunter2"})
+        );
+    }
+}
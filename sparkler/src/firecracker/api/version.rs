@@ -0,0 +1,154 @@
+//! Firecracker release compatibility
+//!
+//! The Firecracker API has grown over time, and older releases reject requests for newer endpoints or fields with a generic 400 error.
+//! [`Client`](super::Client) detects which release it is talking to and checks requests against [`Feature::since`] first, so that using a
+//! feature the VMM doesn't have fails with an [`Error::Unsupported`](super::Error::Unsupported) naming the feature and version instead.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::Error;
+
+/// A Firecracker release version, as reported by `GET /version` or [`InstanceInfo::vmm_version`](super::InstanceInfo::vmm_version).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Whether this release supports `feature`.
+    pub fn supports(self, feature: Feature) -> bool {
+        self >= feature.since()
+    }
+
+    /// Returns an [`Error::Unsupported`] if this release does not support `feature`.
+    pub fn require(self, feature: Feature) -> Result<(), Error> {
+        if self.supports(feature) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported {
+                feature,
+                version: self,
+            })
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    /// Parses versions like `0.25.0`, `v1.1.0`, or `1.0.0-dev`. Pre-release and build suffixes are ignored.
+    fn from_str(s: &str) -> Result<Version, Error> {
+        let invalid = || Error::InvalidVersion(s.to_string());
+
+        let core = s
+            .trim()
+            .trim_start_matches('v')
+            .split(&['-', '+'][..])
+            .next()
+            .unwrap_or_default();
+        let mut parts = core.split('.').map(|part| part.parse::<u32>());
+        let major = parts.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+        let minor = parts.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+        let patch = match parts.next() {
+            Some(patch) => patch.map_err(|_| invalid())?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Version::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// API features that are not available in every Firecracker release.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Creating and loading snapshots
+    Snapshots,
+    /// Diff snapshots, which only contain memory pages written since the previous snapshot
+    DiffSnapshots,
+    /// The balloon device
+    Balloon,
+    /// The `smt` machine configuration field, which older releases call `ht_enabled`
+    Smt,
+    /// MMDS version 2, with session tokens
+    MmdsV2,
+    /// Choosing a memory backend, such as userfaultfd, when loading a snapshot
+    MemoryBackend,
+    /// The virtio-rng entropy device
+    Entropy,
+    /// Custom CPU templates, set with `PUT /cpu-config`
+    CustomCpuTemplates,
+}
+
+impl Feature {
+    /// First Firecracker release supporting this feature.
+    pub fn since(self) -> Version {
+        match self {
+            Feature::Snapshots => Version::new(0, 23, 0),
+            Feature::DiffSnapshots => Version::new(0, 24, 0),
+            Feature::Balloon => Version::new(0, 24, 0),
+            Feature::Smt => Version::new(1, 0, 0),
+            Feature::MmdsV2 => Version::new(1, 0, 0),
+            Feature::MemoryBackend => Version::new(1, 1, 0),
+            Feature::Entropy => Version::new(1, 4, 0),
+            Feature::CustomCpuTemplates => Version::new(1, 4, 0),
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Feature::Snapshots => "snapshots",
+            Feature::DiffSnapshots => "diff snapshots",
+            Feature::Balloon => "the balloon device",
+            Feature::Smt => "the smt machine configuration field",
+            Feature::MmdsV2 => "MMDS version 2",
+            Feature::MemoryBackend => "snapshot memory backends",
+            Feature::Entropy => "the entropy device",
+            Feature::CustomCpuTemplates => "custom CPU templates",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!("0.25.0".parse::<Version>().unwrap(), Version::new(0, 25, 0));
+        assert_eq!("v1.1.2".parse::<Version>().unwrap(), Version::new(1, 1, 2));
+        assert_eq!(
+            "1.0.0-dev".parse::<Version>().unwrap(),
+            Version::new(1, 0, 0)
+        );
+        assert_eq!("1.4".parse::<Version>().unwrap(), Version::new(1, 4, 0));
+        assert!("firecracker".parse::<Version>().is_err());
+        assert!("1.2.3.4".parse::<Version>().is_err());
+
+        assert!(Version::new(0, 24, 1).supports(Feature::Balloon));
+        assert!(!Version::new(0, 23, 0).supports(Feature::Balloon));
+        assert!(Version::new(1, 0, 0).supports(Feature::MmdsV2));
+    }
+}
@@ -0,0 +1,294 @@
+//! Memory reclamation across microVMs using balloon devices
+//!
+//! When many microVMs share a host, their combined memory size usually exceeds what the host can back. Balloon devices let the host ask a guest
+//! to give memory back. [`BalloonPolicy`] inflates the balloons of idle VMs when the host runs low on memory, and deflates them again once
+//! there is room to spare.
+
+use std::fs;
+
+use tracing::{debug, info, warn};
+
+use super::api::{BalloonUpdate, Client};
+use crate::Error;
+
+/// Source of host memory information
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// Thresholds controlling when balloons are inflated and deflated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BalloonPolicy {
+    /// Inflate balloons on idle VMs when the host has less than this much available memory.
+    pub min_host_available_mib: u64,
+
+    /// Deflate balloons once the host has at least this much available memory. This should be comfortably above
+    /// [`min_host_available_mib`](BalloonPolicy::min_host_available_mib) to avoid oscillating.
+    pub deflate_host_available_mib: u64,
+
+    /// Amount to grow an idle VM's balloon by in each step.
+    pub inflate_step_mib: u32,
+
+    /// Largest balloon to inflate in any one VM.
+    pub max_balloon_mib: u32,
+}
+
+/// A VM whose balloon is managed by a [`BalloonPolicy`].
+pub struct BalloonTarget<'a> {
+    /// Name of the VM, for logging
+    pub name: &'a str,
+
+    /// API client for the VM. The VM must have a balloon device.
+    pub client: &'a Client,
+
+    /// Whether the VM is currently idle, and so can have memory taken away.
+    pub idle: bool,
+}
+
+/// A change to a VM's balloon made by [`BalloonPolicy::apply`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BalloonAdjustment {
+    /// Name of the VM
+    pub name: String,
+
+    /// Previous balloon size
+    pub from_mib: u32,
+
+    /// New balloon size
+    pub to_mib: u32,
+}
+
+impl BalloonPolicy {
+    /// Checks host memory and adjusts the balloons of `targets` accordingly. Returns the adjustments that were made. See
+    /// [`BalloonPolicy::adjust`].
+    pub async fn apply(
+        &self,
+        targets: &[BalloonTarget<'_>],
+    ) -> Result<Vec<BalloonAdjustment>, Error> {
+        let available_mib = host_available_mib()?;
+        debug!("Host has {} MiB available", available_mib);
+        Ok(self.adjust(available_mib, targets).await)
+    }
+
+    /// Adjusts the balloons of `targets` for a host with `available_mib` of memory available. Returns the adjustments that were made.
+    ///
+    /// If the host is low on memory, idle VMs' balloons are each grown by one step until the shortfall is covered. If the host has plenty of
+    /// memory, balloons in busy VMs are deflated completely, and balloons in idle VMs are shrunk by one step. Targets whose balloon can't be
+    /// read or resized are logged and skipped.
+    pub async fn adjust(
+        &self,
+        available_mib: u64,
+        targets: &[BalloonTarget<'_>],
+    ) -> Vec<BalloonAdjustment> {
+        let mut adjustments = Vec::new();
+
+        if available_mib < self.min_host_available_mib {
+            let mut shortfall = self.min_host_available_mib - available_mib;
+            for target in targets.iter().filter(|target| target.idle) {
+                if shortfall == 0 {
+                    break;
+                }
+
+                let current = match current_mib(target).await {
+                    Some(current) => current,
+                    None => continue,
+                };
+                let new = current
+                    .saturating_add(self.inflate_step_mib)
+                    .min(self.max_balloon_mib);
+                if new > current && self.resize(target, current, new, &mut adjustments).await {
+                    shortfall = shortfall.saturating_sub(u64::from(new - current));
+                }
+            }
+        } else if available_mib >= self.deflate_host_available_mib {
+            for target in targets {
+                let current = match current_mib(target).await {
+                    Some(current) => current,
+                    None => continue,
+                };
+                let new = if target.idle {
+                    current.saturating_sub(self.inflate_step_mib)
+                } else {
+                    0
+                };
+                if new < current {
+                    self.resize(target, current, new, &mut adjustments).await;
+                }
+            }
+        }
+
+        adjustments
+    }
+
+    /// Resizes the balloon of `target`, recording the adjustment. Returns whether it succeeded; failures are logged.
+    async fn resize(
+        &self,
+        target: &BalloonTarget<'_>,
+        from_mib: u32,
+        to_mib: u32,
+        adjustments: &mut Vec<BalloonAdjustment>,
+    ) -> bool {
+        info!(
+            "Resizing balloon for {} from {} MiB to {} MiB",
+            target.name, from_mib, to_mib
+        );
+        if let Err(error) = target
+            .client
+            .patch_balloon(&BalloonUpdate { amount_mib: to_mib })
+            .await
+        {
+            warn!("Could not resize balloon for {}: {}", target.name, error);
+            return false;
+        }
+        adjustments.push(BalloonAdjustment {
+            name: target.name.to_string(),
+            from_mib,
+            to_mib,
+        });
+        true
+    }
+}
+
+/// Current balloon size of `target`, or `None` if it can't be read, such as when the VM has no balloon device.
+async fn current_mib(target: &BalloonTarget<'_>) -> Option<u32> {
+    match target.client.get_balloon().await {
+        Ok(balloon) => Some(balloon.amount_mib),
+        Err(error) => {
+            warn!("Could not get balloon for {}: {}", target.name, error);
+            None
+        }
+    }
+}
+
+/// Returns an estimate of how much memory the host has available for new allocations, in MiB.
+pub fn host_available_mib() -> Result<u64, Error> {
+    let meminfo = fs::read_to_string(MEMINFO_PATH).map_err(|error| Error::Io {
+        context: format!("could not read {}", MEMINFO_PATH),
+        error,
+    })?;
+    parse_available_kib(&meminfo)
+        .map(|kib| kib / 1024)
+        .ok_or_else(|| Error::Io {
+            context: format!("could not find MemAvailable in {}", MEMINFO_PATH),
+            error: std::io::ErrorKind::InvalidData.into(),
+        })
+}
+
+/// Parses the `MemAvailable` line of `/proc/meminfo`, which is in KiB.
+fn parse_available_kib(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix("MemAvailable:")?;
+        value.trim().trim_end_matches("kB").trim().parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::api::mock::MockServer;
+    use super::super::api::{Balloon, InstanceState};
+    use super::*;
+
+    const POLICY: BalloonPolicy = BalloonPolicy {
+        min_host_available_mib: 1024,
+        deflate_host_available_mib: 4096,
+        inflate_step_mib: 128,
+        max_balloon_mib: 256,
+    };
+
+    /// A running VM with a balloon of `amount_mib`, or without a balloon device if that is `None`.
+    fn server(amount_mib: Option<u32>) -> MockServer {
+        let server = MockServer::start_temp().unwrap();
+        let mut state = server.state();
+        state.instance_state = InstanceState::Running;
+        state.balloon = amount_mib.map(|amount_mib| Balloon {
+            amount_mib,
+            deflate_on_oom: true,
+            stats_polling_interval_s: None,
+        });
+        drop(state);
+        server
+    }
+
+    fn adjustment(name: &str, from_mib: u32, to_mib: u32) -> BalloonAdjustment {
+        BalloonAdjustment {
+            name: name.into(),
+            from_mib,
+            to_mib,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inflate() {
+        let servers: Vec<_> = [Some(0), None, Some(0), Some(200), Some(0), Some(0)]
+            .iter()
+            .map(|&amount_mib| server(amount_mib))
+            .collect();
+        let clients: Vec<_> = servers.iter().map(MockServer::client).collect();
+        let names = ["busy", "no-balloon", "a", "b", "c", "d"];
+        let targets: Vec<_> = names
+            .iter()
+            .zip(&clients)
+            .map(|(&name, client)| BalloonTarget {
+                name,
+                client,
+                idle: name != "busy",
+            })
+            .collect();
+
+        // 224 MiB short: only idle VMs are inflated, by a step each but no further than the maximum, until the shortfall is covered
+        let adjustments = POLICY.adjust(800, &targets).await;
+        assert_eq!(
+            adjustments,
+            vec![
+                adjustment("a", 0, 128),
+                adjustment("b", 200, 256),
+                adjustment("c", 0, 128),
+            ]
+        );
+        assert_eq!(servers[0].state().balloon.as_ref().unwrap().amount_mib, 0);
+        assert_eq!(servers[3].state().balloon.as_ref().unwrap().amount_mib, 256);
+        assert_eq!(servers[5].state().balloon.as_ref().unwrap().amount_mib, 0);
+
+        // Between the thresholds, nothing changes
+        assert!(POLICY.adjust(2048, &targets).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deflate() {
+        let servers: Vec<_> = [Some(100), Some(200), Some(0)]
+            .iter()
+            .map(|&amount_mib| server(amount_mib))
+            .collect();
+        let clients: Vec<_> = servers.iter().map(MockServer::client).collect();
+        let targets = [
+            BalloonTarget {
+                name: "busy",
+                client: &clients[0],
+                idle: false,
+            },
+            BalloonTarget {
+                name: "idle",
+                client: &clients[1],
+                idle: true,
+            },
+            BalloonTarget {
+                name: "empty",
+                client: &clients[2],
+                idle: true,
+            },
+        ];
+
+        // Busy VMs get all their memory back, and idle ones a step at a time
+        let adjustments = POLICY.adjust(5000, &targets).await;
+        assert_eq!(
+            adjustments,
+            vec![adjustment("busy", 100, 0), adjustment("idle", 200, 72)]
+        );
+        assert_eq!(servers[1].state().balloon.as_ref().unwrap().amount_mib, 72);
+    }
+
+    #[test]
+    fn test_parse_available() {
+        let meminfo = "MemTotal:       16316412 kB\nMemFree:         1027548 kB\nMemAvailable:    9438252 kB\nBuffers:          612028 kB\n";
+        assert_eq!(parse_available_kib(meminfo), Some(9438252));
+        assert_eq!(parse_available_kib("MemTotal: 1 kB\n"), None);
+    }
+}
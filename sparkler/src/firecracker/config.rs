@@ -0,0 +1,265 @@
+//! Complete microVM definitions
+//!
+//! A [`VmConfig`] describes everything about a microVM that Firecracker needs before boot. It can either be applied through the API, one
+//! resource at a time, or written into the jail as a configuration file that Firecracker reads at startup with `--config-file`. The JSON
+//! format matches Firecracker's configuration file, so existing files can be loaded with [`serde_json`]. The one exception is
+//! [`VmConfig::cpu_config`]: Firecracker's file refers to a separate CPU template file, while `VmConfig` holds the template itself.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+use nix::unistd::chown;
+use serde::{Deserialize, Serialize};
+
+use super::api::{
+    self, Balloon, BootSource, Client, CpuConfig, Drive, EntropyDevice, Logger,
+    MachineConfiguration, Metrics, MmdsConfig, NetworkInterface, Vsock,
+};
+use super::jailer;
+use crate::Error;
+
+/// Path of the configuration file inside the jail
+pub const CONFIG_FILE: &str = "/vm_config.json";
+
+/// Path of the custom CPU template inside the jail, if there is one
+pub const CPU_CONFIG_FILE: &str = "/cpu_config.json";
+
+/// Full pre-boot configuration for a microVM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VmConfig {
+    pub boot_source: BootSource,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drives: Vec<Drive>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_config: Option<MachineConfiguration>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network_interfaces: Vec<NetworkInterface>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vsock: Option<Vsock>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logger: Option<Logger>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Metrics>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mmds_config: Option<MmdsConfig>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balloon: Option<Balloon>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entropy: Option<EntropyDevice>,
+
+    /// Custom CPU template
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_config: Option<CpuConfig>,
+}
+
+impl VmConfig {
+    /// Creates a configuration that boots `boot_source` with Firecracker's defaults for everything else.
+    pub fn new(boot_source: BootSource) -> VmConfig {
+        VmConfig {
+            boot_source,
+            drives: Vec::new(),
+            machine_config: None,
+            network_interfaces: Vec::new(),
+            vsock: None,
+            logger: None,
+            metrics: None,
+            mmds_config: None,
+            balloon: None,
+            entropy: None,
+            cpu_config: None,
+        }
+    }
+
+    /// Configures the microVM through the API. This does not start the microVM; use
+    /// [`ActionType::InstanceStart`](api::ActionType::InstanceStart) afterwards.
+    pub async fn apply(&self, client: &Client) -> Result<(), api::Error> {
+        // The logger and metrics go first, so that they capture any problems with the rest of the configuration
+        if let Some(logger) = &self.logger {
+            client.set_logger(logger).await?;
+        }
+        if let Some(metrics) = &self.metrics {
+            client.set_metrics(metrics).await?;
+        }
+
+        client.set_boot_source(&self.boot_source).await?;
+        if let Some(machine_config) = &self.machine_config {
+            client.set_machine_config(machine_config).await?;
+        }
+        if let Some(cpu_config) = &self.cpu_config {
+            client.set_cpu_config(cpu_config).await?;
+        }
+        for drive in &self.drives {
+            client.set_drive(drive).await?;
+        }
+        for interface in &self.network_interfaces {
+            client.set_network_interface(interface).await?;
+        }
+        if let Some(vsock) = &self.vsock {
+            client.set_vsock(vsock).await?;
+        }
+        // MMDS configuration refers to network interfaces, so they must already exist
+        if let Some(mmds_config) = &self.mmds_config {
+            client.set_mmds_config(mmds_config).await?;
+        }
+        if let Some(balloon) = &self.balloon {
+            client.set_balloon(balloon).await?;
+        }
+        if let Some(entropy) = &self.entropy {
+            client.set_entropy_device(entropy).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Firecracker arguments for booting from the configuration file written by [`VmConfig::write_to_jail`]. These should be passed to
+    /// [`ConfigBuilder::firecracker_args`](jailer::ConfigBuilder::firecracker_args).
+    pub fn firecracker_args() -> Vec<OsString> {
+        vec!["--config-file".into(), CONFIG_FILE.into()]
+    }
+
+    /// Writes this configuration to [`CONFIG_FILE`] inside the jail, so Firecracker boots from it when started with
+    /// [`VmConfig::firecracker_args`]. If there is a custom CPU template, it's written to [`CPU_CONFIG_FILE`]. Returns the path to the
+    /// configuration file on the host.
+    pub fn write_to_jail(&self, jailer_config: &jailer::Config<'_>) -> Result<PathBuf, Error> {
+        let chroot_path = jailer_config.chroot_path();
+        fs::create_dir_all(&chroot_path).map_err(|error| Error::Io {
+            context: format!("could not create jail directory {}", chroot_path.display()),
+            error,
+        })?;
+
+        let mut contents = serde_json::to_value(self).expect("malformed VM configuration");
+        if let Some(cpu_config) = &self.cpu_config {
+            write_jail_file(jailer_config, CPU_CONFIG_FILE, cpu_config)?;
+            contents["cpu-config"] = CPU_CONFIG_FILE.into();
+        }
+
+        write_jail_file(jailer_config, CONFIG_FILE, &contents)
+    }
+}
+
+/// Writes `value` as JSON to `jail_path` inside the jail, owned by the jail user. Returns the path to the file on the host.
+fn write_jail_file<T: Serialize>(
+    jailer_config: &jailer::Config<'_>,
+    jail_path: &str,
+    value: &T,
+) -> Result<PathBuf, Error> {
+    let path = jailer_config
+        .chroot_path()
+        .join(jail_path.trim_start_matches('/'));
+    let contents = serde_json::to_vec_pretty(value).expect("malformed VM configuration");
+    fs::write(&path, contents).map_err(|error| Error::Io {
+        context: format!("could not write VM configuration {}", path.display()),
+        error,
+    })?;
+    chown(
+        &path,
+        Some(jailer_config.user()),
+        Some(jailer_config.group()),
+    )
+    .map_err(|error| Error::System {
+        context: format!("could not change ownership of {}", path.display()),
+        error,
+    })?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_config_file_format() {
+        let mut config = VmConfig::new(BootSource {
+            kernel_image_path: "vmlinux.bin".into(),
+            initrd_path: None,
+            boot_args: Some("console=ttyS0".into()),
+        });
+        config.drives.push(Drive {
+            drive_id: "rootfs".into(),
+            is_read_only: false,
+            is_root_device: true,
+            path_on_host: "rootfs.ext4".into(),
+            partuuid: None,
+            rate_limiter: None,
+        });
+        config.machine_config = Some(MachineConfiguration {
+            vcpu_count: 2,
+            mem_size_mib: 1024,
+            smt: false,
+            track_dirty_pages: false,
+            cpu_template: None,
+        });
+
+        let expected = json!({
+            "boot-source": {
+                "kernel_image_path": "vmlinux.bin",
+                "boot_args": "console=ttyS0"
+            },
+            "drives": [{
+                "drive_id": "rootfs",
+                "is_read_only": false,
+                "is_root_device": true,
+                "path_on_host": "rootfs.ext4"
+            }],
+            "machine-config": {
+                "vcpu_count": 2,
+                "mem_size_mib": 1024,
+                "smt": false
+            }
+        });
+
+        assert_eq!(serde_json::to_value(&config).unwrap(), expected);
+        assert_eq!(
+            serde_json::from_value::<VmConfig>(expected).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn test_write_cpu_config() {
+        let chroot_base = TempDir::new("config");
+        let jailer_config = jailer::ConfigBuilder::default()
+            .id("config")
+            .user(nix::unistd::Uid::current())
+            .group(nix::unistd::Gid::current())
+            .chroot_base(chroot_base.path())
+            .build()
+            .unwrap();
+
+        let mut config = VmConfig::new(BootSource {
+            kernel_image_path: "vmlinux.bin".into(),
+            initrd_path: None,
+            boot_args: None,
+        });
+        config.cpu_config = Some(CpuConfig {
+            kvm_capabilities: vec!["!56".into()],
+            ..CpuConfig::default()
+        });
+
+        let path = config.write_to_jail(&jailer_config).unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["cpu-config"], json!(CPU_CONFIG_FILE));
+
+        let template = jailer_config
+            .chroot_path()
+            .join(CPU_CONFIG_FILE.trim_start_matches('/'));
+        let template: serde_json::Value =
+            serde_json::from_slice(&fs::read(template).unwrap()).unwrap();
+        assert_eq!(template, json!({"kvm_capabilities": ["!56"]}));
+    }
+}
@@ -0,0 +1,516 @@
+//! Guest serial console capture
+//!
+//! With `console=ttyS0` on the kernel command line, the guest's serial console is Firecracker's standard input and output. [`spawn`] starts
+//! the jailer attached to a pseudoterminal instead of sparkler's own stdio. A background thread copies everything the guest prints into a
+//! [`RotatingLog`] in the VM's state directory, and to any terminals [attached](Console::attach) to the console.
+//!
+//! An attached terminal can type into the guest console until it sends the detach sequence (by default Ctrl-], as in `telnet`), which
+//! disconnects it while leaving the VM and the log running.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::openpty;
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd::pipe2;
+use tracing::{debug, warn};
+use unshare::{Child, Stdio};
+
+use super::jailer::{self, Config};
+use crate::Error;
+
+/// File name of the console log, in the VM's state directory
+const LOG_FILE: &str = "console.log";
+
+/// Default size at which the console log is rotated
+pub const DEFAULT_LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Default number of rotated console logs to keep
+pub const DEFAULT_LOG_KEEP: usize = 3;
+
+/// Default detach sequence: Ctrl-]
+pub const DEFAULT_DETACH_KEYS: &[u8] = &[0x1d];
+
+/// Size of reads from the console
+const BUFFER_SIZE: usize = 4096;
+
+/// Path to the console log of the VM described by `config`.
+///
+/// This takes the form `$chroot_base/$(basename $firecracker_binary)/$id/console.log`, alongside the `root` chroot directory.
+pub fn log_path(config: &Config<'_>) -> PathBuf {
+    config
+        .chroot_path()
+        .parent()
+        .expect("chroot path has no parent")
+        .join(LOG_FILE)
+}
+
+/// Starts the jailer with its standard input, output, and error connected to a new pseudoterminal, and starts capturing the guest console to
+/// [`log_path`].
+///
+/// The jail must not be configured to daemonize, since the jailer then redirects its standard streams to `/dev/null` and there would be no
+/// console to capture.
+pub fn spawn(config: &Config<'_>) -> Result<(Child, Console), Error> {
+    if config.daemonize() {
+        return Err(Error::InvalidJailerConfig(format!(
+            "jail {} is configured to daemonize, so its console can't be captured",
+            config.id()
+        )));
+    }
+    config.check_cgroups()?;
+
+    let log_path = log_path(config);
+    if let Some(parent) = log_path.parent() {
+        fs::create_dir_all(parent).map_err(|error| Error::Io {
+            context: format!("could not create VM state directory {}", parent.display()),
+            error,
+        })?;
+    }
+    let log = RotatingLog::open(&log_path, DEFAULT_LOG_MAX_BYTES, DEFAULT_LOG_KEEP)?;
+
+    let pty = openpty(None, None).map_err(|error| Error::System {
+        context: "could not open console pseudoterminal".into(),
+        error,
+    })?;
+    // SAFETY: openpty returned new file descriptors that nothing else owns
+    let (master, slave) = unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+    // Keep the master side out of the jailer, so the console closes when Firecracker exits
+    fcntl(master.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(|error| {
+        Error::System {
+            context: "could not configure console pseudoterminal".into(),
+            error,
+        }
+    })?;
+
+    let stdio = || {
+        Stdio::dup_file(&slave).map_err(|error| Error::Io {
+            context: "could not duplicate console pseudoterminal".into(),
+            error,
+        })
+    };
+    let mut command = jailer::build_command(config);
+    command.stdin(stdio()?).stdout(stdio()?).stderr(stdio()?);
+    let child = command.spawn().map_err(Error::Jailer)?;
+    drop(slave);
+
+    let console = Console::start(master, log)?;
+    Ok((child, console))
+}
+
+/// A captured guest console.
+pub struct Console {
+    /// Pseudoterminal master, for writing input to the guest
+    input: Mutex<File>,
+    subscribers: Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>>,
+    next_subscriber: AtomicU64,
+    log_path: PathBuf,
+    /// Read end of a pipe whose write end is closed when the guest console closes, to wake up attached terminals waiting for input
+    closed: File,
+}
+
+impl Console {
+    /// Starts copying output from `source` into `log` and to attached terminals. `source` is also written to for console input.
+    fn start(source: File, mut log: RotatingLog) -> Result<Console, Error> {
+        let input = source.try_clone().map_err(|error| Error::Io {
+            context: "could not duplicate console pseudoterminal".into(),
+            error,
+        })?;
+        let subscribers: Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>> = Arc::default();
+        let log_path = log.path().to_path_buf();
+        let (closed, closed_writer) = pipe2(OFlag::O_CLOEXEC).map_err(|error| Error::System {
+            context: "could not create console pipe".into(),
+            error,
+        })?;
+        // SAFETY: pipe2 returned new file descriptors that nothing else owns
+        let (closed, closed_writer) =
+            unsafe { (File::from_raw_fd(closed), File::from_raw_fd(closed_writer)) };
+
+        let reader_subscribers = subscribers.clone();
+        thread::spawn(move || {
+            let mut source = source;
+            let mut buf = [0u8; BUFFER_SIZE];
+            loop {
+                let n = match source.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    // Reading a pseudoterminal master fails with EIO once every slave is closed
+                    Err(err) if err.raw_os_error() == Some(nix::libc::EIO) => break,
+                    Err(err) => {
+                        warn!("Reading guest console failed: {}", err);
+                        break;
+                    }
+                };
+
+                if let Err(err) = log.write_all(&buf[..n]) {
+                    warn!(
+                        "Writing console log {} failed: {}",
+                        log.path().display(),
+                        err
+                    );
+                }
+                reader_subscribers
+                    .lock()
+                    .unwrap()
+                    .retain(|_, subscriber| subscriber.send(buf[..n].to_vec()).is_ok());
+            }
+            debug!("Guest console closed");
+            // Disconnect attached terminals
+            reader_subscribers.lock().unwrap().clear();
+            drop(closed_writer);
+        });
+
+        Ok(Console {
+            input: Mutex::new(input),
+            subscribers,
+            next_subscriber: AtomicU64::new(0),
+            log_path,
+            closed,
+        })
+    }
+
+    /// Path to the current console log.
+    pub fn log_path(&self) -> &Path {
+        &self.log_path
+    }
+
+    /// Sends `data` to the guest console, as if typed.
+    pub fn write(&self, data: &[u8]) -> io::Result<()> {
+        self.input.lock().unwrap().write_all(data)
+    }
+
+    /// Connects `input` and `output` to the console. Console output is copied to `output`, and bytes read from `input` are sent to the guest,
+    /// until `input` contains `detach_keys` or reaches end-of-file, or the guest console closes. The detach sequence itself is not sent to
+    /// the guest. `input` is read directly from its file descriptor, bypassing any buffering.
+    ///
+    /// Output from before attaching is only available in the [log](Console::log_path).
+    pub fn attach<R, W>(&self, input: &R, mut output: W, detach_keys: &[u8]) -> io::Result<()>
+    where
+        R: AsRawFd,
+        W: Write + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let id = self.next_subscriber.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().unwrap().insert(id, sender);
+
+        let writer = thread::spawn(move || -> io::Result<()> {
+            for data in receiver {
+                output.write_all(&data)?;
+                output.flush()?;
+            }
+            Ok(())
+        });
+
+        // SAFETY: the descriptor stays open while `input` is borrowed, and is not closed when this is dropped
+        let mut input = ManuallyDrop::new(unsafe { File::from_raw_fd(input.as_raw_fd()) });
+        let mut detach = DetachMatcher::new(detach_keys);
+        let mut buf = [0u8; BUFFER_SIZE];
+        let result = loop {
+            // Wait for input, or for the guest console to close
+            let mut fds = [
+                PollFd::new(input.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(self.closed.as_raw_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, -1) {
+                Ok(_) => {}
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(error) => break Err(io::Error::other(error)),
+            }
+            if fds[1].revents().is_some_and(|events| !events.is_empty()) {
+                break Ok(());
+            }
+
+            let n = match input.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            };
+
+            let (forward, detached) = detach.feed(&buf[..n]);
+            if !forward.is_empty() {
+                if let Err(err) = self.write(&forward) {
+                    break Err(err);
+                }
+            }
+            if detached {
+                break Ok(());
+            }
+        };
+
+        // Dropping the sender stops the writer thread
+        self.subscribers.lock().unwrap().remove(&id);
+        let written = writer.join().expect("console writer panicked");
+        result.and(written)
+    }
+
+    /// Attaches the host terminal to the console, with the terminal in raw mode so that keys like Ctrl-C reach the guest. Type
+    /// `detach_keys` to detach.
+    pub fn attach_terminal(&self, detach_keys: &[u8]) -> Result<(), Error> {
+        let stdin = io::stdin();
+        let _raw = RawMode::enable(stdin.as_raw_fd())?;
+        self.attach(&stdin, io::stdout(), detach_keys)
+            .map_err(|error| Error::Io {
+                context: "console attach failed".into(),
+                error,
+            })
+    }
+}
+
+/// Finds the detach sequence in console input, which may be split across reads. This is the Knuth-Morris-Pratt algorithm, so a partial
+/// match that fails can still end in the start of another one, as when `aab` follows the first `a` of `aaab`.
+struct DetachMatcher<'a> {
+    keys: &'a [u8],
+    /// For each prefix `keys[..=i]`, the length of the longest proper prefix of `keys` that is also a suffix of it
+    failure: Vec<usize>,
+    /// Number of bytes of `keys` matched so far. These are held back until it's known whether they're part of the detach sequence.
+    matched: usize,
+}
+
+impl<'a> DetachMatcher<'a> {
+    fn new(keys: &'a [u8]) -> DetachMatcher<'a> {
+        let mut failure = vec![0; keys.len()];
+        let mut length = 0;
+        for i in 1..keys.len() {
+            while length > 0 && keys[i] != keys[length] {
+                length = failure[length - 1];
+            }
+            if keys[i] == keys[length] {
+                length += 1;
+            }
+            failure[i] = length;
+        }
+        DetachMatcher {
+            keys,
+            failure,
+            matched: 0,
+        }
+    }
+
+    /// Processes a chunk of input, returning the bytes to forward to the guest and whether the detach sequence was completed.
+    fn feed(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut forward = Vec::with_capacity(input.len());
+        if self.keys.is_empty() {
+            forward.extend_from_slice(input);
+            return (forward, false);
+        }
+
+        for &byte in input {
+            // Fall back to shorter partial matches, releasing the held-back bytes that are no longer part of one
+            while self.matched > 0 && byte != self.keys[self.matched] {
+                let fallback = self.failure[self.matched - 1];
+                forward.extend_from_slice(&self.keys[..self.matched - fallback]);
+                self.matched = fallback;
+            }
+            if byte == self.keys[self.matched] {
+                self.matched += 1;
+                if self.matched == self.keys.len() {
+                    return (forward, true);
+                }
+            } else {
+                forward.push(byte);
+            }
+        }
+        (forward, false)
+    }
+}
+
+/// Puts a terminal in raw mode, restoring its previous settings when dropped.
+struct RawMode {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> Result<RawMode, Error> {
+        let original = termios::tcgetattr(fd).map_err(|error| Error::System {
+            context: "could not get terminal attributes".into(),
+            error,
+        })?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw).map_err(|error| Error::System {
+            context: "could not put terminal in raw mode".into(),
+            error,
+        })?;
+        Ok(RawMode { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(err) = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.original) {
+            eprintln!("Restoring terminal attributes failed: {}", err);
+        }
+    }
+}
+
+/// A log file that is rotated once it reaches a maximum size. Rotated logs are renamed to `<path>.1`, `<path>.2`, and so on, with the
+/// oldest removed once there are more than `keep`.
+pub struct RotatingLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    /// Opens the log at `path`, appending to it if it exists.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        max_bytes: u64,
+        keep: usize,
+    ) -> Result<RotatingLog, Error> {
+        let path = path.into();
+        let file = open_log(&path).map_err(|error| Error::Io {
+            context: format!("could not open console log {}", path.display()),
+            error,
+        })?;
+        let size = file
+            .metadata()
+            .map_err(|error| Error::Io {
+                context: format!("could not read metadata for {}", path.display()),
+                error,
+            })?
+            .len();
+        Ok(RotatingLog {
+            path,
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    /// Path to the current log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = open_log(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::IntoRawFd;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_detach_keys() {
+        // Ctrl-P Ctrl-Q, as in Docker
+        let mut detach = DetachMatcher::new(&[0x10, 0x11]);
+        assert_eq!(detach.feed(b"ls\r"), (b"ls\r".to_vec(), false));
+        assert_eq!(detach.feed(b"a\x10"), (b"a".to_vec(), false));
+        assert_eq!(detach.feed(b"\x10b"), (b"\x10\x10b".to_vec(), false));
+        assert_eq!(detach.feed(b"\x10\x11c"), (Vec::new(), true));
+
+        // A failed partial match can overlap the real one
+        let mut detach = DetachMatcher::new(b"aab");
+        assert_eq!(detach.feed(b"xaaa"), (b"xa".to_vec(), false));
+        assert_eq!(detach.feed(b"b"), (Vec::new(), true));
+        let mut detach = DetachMatcher::new(b"abac");
+        assert_eq!(detach.feed(b"ababac"), (b"ab".to_vec(), true));
+    }
+
+    #[test]
+    fn test_attach_console_closed() {
+        use std::os::unix::net::UnixStream;
+        use std::time::Duration;
+
+        let dir = TempDir::new("attach");
+        let log = RotatingLog::open(dir.path().join(LOG_FILE), DEFAULT_LOG_MAX_BYTES, 0).unwrap();
+
+        // Stand-ins for the pseudoterminal and the attached terminal's input
+        let (master, mut guest) = UnixStream::pair().unwrap();
+        let (terminal, mut keyboard) = UnixStream::pair().unwrap();
+        // SAFETY: into_raw_fd gave up ownership of the descriptor
+        let console = Arc::new(
+            Console::start(unsafe { File::from_raw_fd(master.into_raw_fd()) }, log).unwrap(),
+        );
+
+        let (done, finished) = mpsc::channel();
+        let attached = console.clone();
+        thread::spawn(move || {
+            let result = attached.attach(&terminal, io::sink(), DEFAULT_DETACH_KEYS);
+            done.send(result.is_ok()).unwrap();
+        });
+
+        keyboard.write_all(b"ls\r").unwrap();
+        let mut typed = [0u8; 3];
+        guest.read_exact(&mut typed).unwrap();
+        assert_eq!(&typed, b"ls\r");
+
+        // Closing the console detaches, even though the terminal is still open
+        drop(guest);
+        assert!(finished.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_rotating_log() {
+        let dir = TempDir::new("console");
+        let path = dir.path().join(LOG_FILE);
+
+        let mut log = RotatingLog::open(&path, 8, 2).unwrap();
+        for chunk in &["12345", "6789", "abcdefgh", "ijk", "lmnop"] {
+            log.write_all(chunk.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ijklmnop");
+        assert_eq!(fs::read_to_string(log.rotated_path(1)).unwrap(), "abcdefgh");
+        assert_eq!(fs::read_to_string(log.rotated_path(2)).unwrap(), "6789");
+        assert!(!log.rotated_path(3).exists());
+    }
+}
@@ -0,0 +1,320 @@
+//! Daemonized microVMs
+//!
+//! Normally the jailer is killed when the sparkler process that started it exits. With [`ConfigBuilder::daemonize`], the jailer detaches
+//! Firecracker instead, so the microVM outlives sparkler. [`Daemon::spawn`] records the Firecracker PID and API socket in a state file in the
+//! VM's state directory (the parent of [`Config::chroot_path`]), and a later sparkler process can [reattach](Daemon::reattach) to it.
+//!
+//! PIDs are reused, so the state file also records when the process started, and reattaching checks that the process is still the
+//! Firecracker serving this jail before trusting it.
+//!
+//! [`ConfigBuilder::daemonize`]: super::jailer::ConfigBuilder::daemonize
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use nix::libc::pid_t;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::api::Client;
+use super::jailer::{self, Config};
+use crate::Error;
+
+/// File name of the daemon state file, in the VM's state directory
+const STATE_FILE: &str = "daemon.json";
+
+/// A Firecracker process detached from sparkler by the jailer.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Daemon {
+    /// Jail ID
+    id: String,
+    /// PID of the Firecracker process, as seen from the host
+    pid: pid_t,
+    /// Start time of the process, in clock ticks since boot, to detect PID reuse
+    start_time: u64,
+    /// Path on the host to the API socket
+    api_socket_path: PathBuf,
+    /// Directory that Firecracker is jailed in
+    chroot_path: PathBuf,
+}
+
+impl Daemon {
+    /// Path to the state file for the microVM described by `config`.
+    pub fn state_path(config: &Config<'_>) -> PathBuf {
+        config
+            .chroot_path()
+            .parent()
+            .expect("chroot path has no parent")
+            .join(STATE_FILE)
+    }
+
+    /// Starts a detached Firecracker in the jail described by `config`, which must have [`daemonize`](Config::daemonize) set, and records
+    /// it in the state file. This does not wait for the API to become available; use [`Client::wait_ready`] on [`Daemon::client`].
+    ///
+    /// While this process is running, the jailer is still its child, so a thread is left waiting to reap it.
+    pub fn spawn(config: &Config<'_>) -> Result<Daemon, Error> {
+        if !config.daemonize() {
+            return Err(Error::InvalidJailerConfig(format!(
+                "jail {} is not configured to daemonize",
+                config.id()
+            )));
+        }
+
+        let state_path = Daemon::state_path(config);
+        if let Some(parent) = state_path.parent() {
+            fs::create_dir_all(parent).map_err(|error| Error::Io {
+                context: format!("could not create VM state directory {}", parent.display()),
+                error,
+            })?;
+        }
+
+        let mut child = jailer::spawn(config)?;
+        // The jailer execs Firecracker without forking, so this is also Firecracker's PID
+        let pid = child.pid();
+        let daemon = Daemon {
+            id: config.id().to_string(),
+            pid,
+            start_time: process_start_time(pid)?,
+            api_socket_path: config.api_socket_path(),
+            chroot_path: config.chroot_path(),
+        };
+        thread::spawn(move || child.wait());
+
+        daemon.save(&state_path)?;
+        info!(
+            "Started daemonized Firecracker {} for jail {}",
+            pid, daemon.id
+        );
+        Ok(daemon)
+    }
+
+    /// Loads the state file at `state_path` and checks that the microVM it describes is still running.
+    pub fn reattach(state_path: &Path) -> Result<Daemon, Error> {
+        let daemon = Daemon::load(state_path)?;
+        match daemon.check() {
+            Ok(()) => {
+                debug!(
+                    "Reattached to Firecracker {} for jail {}",
+                    daemon.pid, daemon.id
+                );
+                Ok(daemon)
+            }
+            Err(reason) => Err(Error::NotRunning {
+                id: daemon.id,
+                reason,
+            }),
+        }
+    }
+
+    /// Reads a state file without checking whether the microVM is running.
+    pub fn load(state_path: &Path) -> Result<Daemon, Error> {
+        let io_error = |error| Error::Io {
+            context: format!("could not read daemon state {}", state_path.display()),
+            error,
+        };
+        let contents = fs::read(state_path).map_err(io_error)?;
+        serde_json::from_slice(&contents).map_err(|error| io_error(error.into()))
+    }
+
+    /// Writes the state file, replacing it atomically.
+    fn save(&self, state_path: &Path) -> Result<(), Error> {
+        let temp_path = state_path.with_extension("json.tmp");
+        let contents = serde_json::to_vec_pretty(self).expect("malformed daemon state");
+        fs::write(&temp_path, contents)
+            .and_then(|()| fs::rename(&temp_path, state_path))
+            .map_err(|error| Error::Io {
+                context: format!("could not write daemon state {}", state_path.display()),
+                error,
+            })
+    }
+
+    /// Jail ID
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// PID of the Firecracker process
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    /// Path on the host to the Firecracker API socket
+    pub fn api_socket_path(&self) -> &Path {
+        &self.api_socket_path
+    }
+
+    /// Directory that Firecracker is jailed in
+    pub fn chroot_path(&self) -> &Path {
+        &self.chroot_path
+    }
+
+    /// Path to this microVM's state file
+    pub fn path(&self) -> PathBuf {
+        self.chroot_path
+            .parent()
+            .expect("chroot path has no parent")
+            .join(STATE_FILE)
+    }
+
+    /// Returns an API client for the microVM. To manage it as a [`MicroVm`](super::vm::MicroVm), use
+    /// [`MicroVm::attach`](super::vm::MicroVm::attach) with this client, which returns it in whichever state it is in.
+    pub fn client(&self) -> Client {
+        Client::new(self.api_socket_path.clone())
+    }
+
+    /// Whether the recorded process is still running and is the Firecracker for this jail.
+    pub fn is_running(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// Sends `signal` to the Firecracker process, after checking that the PID still refers to it.
+    pub fn signal(&self, signal: Signal) -> Result<(), Error> {
+        if let Err(reason) = self.check() {
+            return Err(Error::NotRunning {
+                id: self.id.clone(),
+                reason,
+            });
+        }
+        kill(Pid::from_raw(self.pid), signal).map_err(|error| Error::System {
+            context: format!("could not send {:?} to Firecracker {}", signal, self.pid),
+            error,
+        })
+    }
+
+    /// Removes the state file, once the microVM has stopped.
+    pub fn remove(&self) -> Result<(), Error> {
+        let path = self.path();
+        fs::remove_file(&path).map_err(|error| Error::Io {
+            context: format!("could not remove daemon state {}", path.display()),
+            error,
+        })
+    }
+
+    /// Checks that the recorded PID still refers to this microVM's Firecracker process, returning why not otherwise.
+    fn check(&self) -> Result<(), String> {
+        let stat = match read_stat(self.pid) {
+            Ok(stat) => stat,
+            Err(_) => return Err(format!("process {} does not exist", self.pid)),
+        };
+        if stat.state == 'Z' {
+            return Err(format!("process {} has exited", self.pid));
+        }
+        if stat.start_time != self.start_time {
+            return Err(format!("PID {} has been reused", self.pid));
+        }
+
+        // The jailer passes the jail ID to Firecracker as `--id`
+        let cmdline = fs::read(format!("/proc/{}/cmdline", self.pid))
+            .map_err(|error| format!("could not read command line of {}: {}", self.pid, error))?;
+        let mut args = cmdline.split(|&b| b == 0);
+        let has_id = args.any(|arg| arg == b"--id") && args.next() == Some(self.id.as_bytes());
+        if !has_id {
+            return Err(format!(
+                "process {} is not running jail {}",
+                self.pid, self.id
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Fields of `/proc/<pid>/stat`
+struct Stat {
+    state: char,
+    start_time: u64,
+}
+
+/// Reads the state and start time of process `pid`. See the `proc(5)` man page.
+fn read_stat(pid: pid_t) -> Result<Stat, Error> {
+    let path = format!("/proc/{}/stat", pid);
+    let invalid = || Error::Io {
+        context: format!("could not parse {}", path),
+        error: std::io::ErrorKind::InvalidData.into(),
+    };
+
+    let stat = fs::read_to_string(&path).map_err(|error| Error::Io {
+        context: format!("could not read {}", path),
+        error,
+    })?;
+    // The command name is in parentheses and may contain spaces, so skip past it. The state is field 3, and the start time is field 22.
+    let fields = stat
+        .rsplit_once(')')
+        .ok_or_else(invalid)?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let state = fields
+        .first()
+        .and_then(|state| state.chars().next())
+        .ok_or_else(invalid)?;
+    let start_time = fields
+        .get(19)
+        .and_then(|start_time| start_time.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok(Stat { state, start_time })
+}
+
+/// Start time of process `pid`, in clock ticks since boot.
+fn process_start_time(pid: pid_t) -> Result<u64, Error> {
+    read_stat(pid).map(|stat| stat.start_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_reattach() {
+        let temp = TempDir::new("daemon");
+        let state_dir = temp.path();
+        fs::create_dir_all(state_dir.join("root")).unwrap();
+
+        // Stands in for Firecracker, with the jail ID on its command line
+        let mut process = Command::new("/bin/sh")
+            .args(["-c", "sleep 10; true", "firecracker", "--id", "daemon"])
+            .spawn()
+            .unwrap();
+        // Give the child time to exec the shell
+        thread::sleep(std::time::Duration::from_millis(100));
+        let pid = process.id() as pid_t;
+        let daemon = Daemon {
+            id: "daemon".into(),
+            pid,
+            start_time: process_start_time(pid).unwrap(),
+            api_socket_path: state_dir.join("root/run/firecracker.socket"),
+            chroot_path: state_dir.join("root"),
+        };
+        daemon.save(&daemon.path()).unwrap();
+
+        let reattached = Daemon::reattach(&state_dir.join(STATE_FILE)).unwrap();
+        assert_eq!(reattached, daemon);
+
+        let other_jail = Daemon {
+            id: "other".into(),
+            ..daemon.clone()
+        };
+        assert!(!other_jail.is_running());
+        let reused = Daemon {
+            start_time: daemon.start_time + 1,
+            ..daemon.clone()
+        };
+        assert!(!reused.is_running());
+
+        daemon.signal(Signal::SIGKILL).unwrap();
+        process.wait().unwrap();
+        assert!(matches!(
+            Daemon::reattach(&daemon.path()),
+            Err(Error::NotRunning { .. })
+        ));
+
+        daemon.remove().unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//! Cleaning up after crashed microVMs
+//!
+//! When sparkler exits without tearing a microVM down, its chroot (`$chroot_base/$(basename $firecracker_binary)/$id/root`), anything
+//! mounted inside it, and its network namespace are left behind, and starting a microVM with the same ID or namespace name fails. [`collect`]
+//! finds jails and network namespaces that no running process belongs to and removes them.
+//!
+//! Only the chroot of a jail is removed. The rest of the jail directory holds state that outlives the microVM, like its snapshot, console
+//! log, and daemon state file, which are left for the user to restore from or inspect. Jails without a chroot are skipped.
+//!
+//! A jail belongs to a process if the process was started with `--id` and the jail ID, which is true of both the jailer and Firecracker.
+//! Jails and namespaces that were modified or created recently are skipped, since another sparkler process may be setting up a microVM it
+//! hasn't started yet. Only namespaces that sparkler created are considered (see [`namespace::list_owned`]).
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use nix::mount::{umount2, MntFlags};
+use tracing::{info, warn};
+
+use super::jailer::{users::UserAllocator, DEFAULT_CHROOT_BASE};
+use crate::network::namespace;
+use crate::Error;
+
+/// Default time since a jail was last modified before it can be collected
+const DEFAULT_MIN_AGE: Duration = Duration::from_secs(60);
+
+/// Name of the chroot directory inside a jail
+const ROOT_DIRECTORY: &str = "root";
+
+/// What [`collect`] cleans up.
+#[derive(Clone, Debug)]
+pub struct GcOptions {
+    /// Base directory that jails are created under
+    pub chroot_base: PathBuf,
+
+    /// Jails modified, and network namespaces created, more recently than this are left alone, even with no running process.
+    pub min_age: Duration,
+
+    /// Whether to delete network namespaces that sparkler created and no process is running in. Namespaces created by anything else are
+    /// always left alone.
+    pub namespaces: bool,
+
+    /// Dynamic user allocator to release the users of collected jails from. This assumes users were allocated with the jail ID as the owner.
+    pub users: Option<UserAllocator>,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            chroot_base: DEFAULT_CHROOT_BASE.into(),
+            min_age: DEFAULT_MIN_AGE,
+            namespaces: false,
+            users: None,
+        }
+    }
+}
+
+/// What [`collect`] cleaned up.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Jail directories whose chroots were removed
+    pub jails: Vec<PathBuf>,
+
+    /// Jail directories that could not be cleaned up. The reasons are logged.
+    pub failed: Vec<PathBuf>,
+
+    /// Network namespaces that were deleted
+    pub namespaces: Vec<String>,
+
+    /// Dynamic users that were released
+    pub users: Vec<u32>,
+}
+
+/// Removes stale jails and network namespaces, as described in `options`. Errors cleaning up individual jails are logged and reported in
+/// [`GcReport::failed`], and errors deleting individual namespaces are logged, rather than stopping collection.
+pub fn collect(options: &GcOptions) -> Result<GcReport, Error> {
+    let mut report = GcReport::default();
+    let live_ids = live_jail_ids()?;
+
+    for jail in jail_directories(&options.chroot_base)? {
+        let id = match jail.file_name().and_then(|id| id.to_str()) {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        if live_ids.contains(&id) || fs::symlink_metadata(jail.join(ROOT_DIRECTORY)).is_err() {
+            continue;
+        }
+        match modified_since(&jail) {
+            Ok(age) if age < options.min_age => continue,
+            Ok(_) => {}
+            Err(error) => {
+                warn!("Could not check age of jail {}: {}", jail.display(), error);
+                continue;
+            }
+        }
+
+        info!("Removing stale jail {}", jail.display());
+        if let Err(error) = remove_chroot(&jail) {
+            warn!("Could not remove jail {}: {}", jail.display(), error);
+            report.failed.push(jail);
+            continue;
+        }
+        report.jails.push(jail);
+
+        if let Some(users) = &options.users {
+            match users.release_owner(&id) {
+                Ok(released) => report.users.extend(released),
+                Err(error) => warn!("Could not release users for jail {}: {}", id, error),
+            }
+        }
+    }
+
+    if options.namespaces {
+        for name in namespace::list_owned()? {
+            let stale = namespace::created(&name).and_then(|created| {
+                let age = SystemTime::now()
+                    .duration_since(created)
+                    .unwrap_or_default();
+                Ok(age >= options.min_age && !namespace::in_use(&name)?)
+            });
+            match stale {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(error) => {
+                    warn!("Could not check network namespace {}: {}", name, error);
+                    continue;
+                }
+            }
+
+            info!("Deleting orphaned network namespace {}", name);
+            match namespace::delete(&name) {
+                Ok(()) => report.namespaces.push(name),
+                Err(error) => warn!("Could not delete network namespace {}: {}", name, error),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Jail directories under `chroot_base`, for every Firecracker binary name.
+fn jail_directories(chroot_base: &Path) -> Result<Vec<PathBuf>, Error> {
+    let list = |path: &Path| -> Result<Vec<PathBuf>, Error> {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => {
+                return Err(Error::Io {
+                    context: format!("could not list {}", path.display()),
+                    error,
+                })
+            }
+        };
+        let mut directories = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|error| Error::Io {
+                context: format!("could not list {}", path.display()),
+                error,
+            })?;
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                directories.push(entry.path());
+            }
+        }
+        Ok(directories)
+    };
+
+    let mut jails = Vec::new();
+    for exec_directory in list(chroot_base)? {
+        jails.extend(list(&exec_directory)?);
+    }
+    Ok(jails)
+}
+
+/// IDs of jails that a running process was started for.
+fn live_jail_ids() -> Result<HashSet<String>, Error> {
+    let processes = fs::read_dir("/proc").map_err(|error| Error::Io {
+        context: "could not list processes".into(),
+        error,
+    })?;
+
+    let mut ids = HashSet::new();
+    for entry in processes.flatten() {
+        // Processes may exit while we're looking
+        let cmdline = match fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let mut args = cmdline.split(|&b| b == 0);
+        while let Some(arg) = args.next() {
+            if arg == b"--id" {
+                if let Some(id) = args.next() {
+                    ids.insert(String::from_utf8_lossy(id).into_owned());
+                }
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Time since `jail` or its chroot was last modified, whichever is more recent.
+fn modified_since(jail: &Path) -> io::Result<Duration> {
+    let mut modified = fs::metadata(jail)?.modified()?;
+    if let Ok(root) = fs::metadata(jail.join(ROOT_DIRECTORY)) {
+        modified = modified.max(root.modified()?);
+    }
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default())
+}
+
+/// Unmounts everything under the chroot of `jail` and removes it. If anything is still mounted afterwards, or the mount table can't be read,
+/// the chroot is left in place, so that files on the host aren't deleted through a bind mount.
+fn remove_chroot(jail: &Path) -> Result<(), Error> {
+    let root = jail.join(ROOT_DIRECTORY);
+    // The mount table has canonical paths, so a jail under a symlink would otherwise appear to have nothing mounted in it
+    let root = &fs::canonicalize(&root).map_err(|error| Error::Io {
+        context: format!("could not resolve {}", root.display()),
+        error,
+    })?;
+
+    // Unmount the deepest mounts first
+    let mut mounts = mounts_under(root)?;
+    mounts.sort_by_key(|mount| std::cmp::Reverse(mount.components().count()));
+    for mount in &mounts {
+        umount2(mount, MntFlags::MNT_DETACH).map_err(|error| Error::System {
+            context: format!("could not unmount {}", mount.display()),
+            error,
+        })?;
+    }
+
+    if let Some(mount) = mounts_under(root)?.first() {
+        return Err(Error::Io {
+            context: format!("{} is still mounted", mount.display()),
+            error: io::ErrorKind::Other.into(),
+        });
+    }
+
+    fs::remove_dir_all(root).map_err(|error| Error::Io {
+        context: format!("could not remove chroot {}", root.display()),
+        error,
+    })
+}
+
+/// Mount points at or under `path`, which must be canonical, from `/proc/self/mountinfo`. See the `proc(5)` man page. Fails if the mount
+/// table can't be read or parsed, rather than reporting nothing mounted.
+fn mounts_under(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(|error| Error::Io {
+        context: "could not read mount table".into(),
+        error,
+    })?;
+
+    let mut mounts = Vec::new();
+    for line in mountinfo.lines() {
+        let mount_point = line.split(' ').nth(4).ok_or_else(|| Error::Io {
+            context: format!("could not parse mount table entry {:?}", line),
+            error: io::ErrorKind::InvalidData.into(),
+        })?;
+        let mount_point = PathBuf::from(unescape_mount_point(mount_point));
+        if mount_point.starts_with(path) {
+            mounts.push(mount_point);
+        }
+    }
+    Ok(mounts)
+}
+
+/// Decodes the octal escapes (like `\040` for a space) used for whitespace and backslashes in mount points.
+fn unescape_mount_point(escaped: &str) -> String {
+    let mut unescaped = Vec::with_capacity(escaped.len());
+    let bytes = escaped.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::thread;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_collect() {
+        let temp = TempDir::new("gc");
+        let base = temp.path();
+        let chroot_base = base.join("jails");
+        let stale = chroot_base.join("firecracker/gc-stale");
+        let live = chroot_base.join("firecracker/gc-live");
+        fs::create_dir_all(stale.join("root/run")).unwrap();
+        fs::create_dir_all(stale.join("snapshot")).unwrap();
+        fs::write(stale.join("snapshot/vmstate"), "state").unwrap();
+        fs::create_dir_all(live.join("root/run")).unwrap();
+
+        let users = UserAllocator::new(base.join("users.json"), 61184..61186);
+        let user = users.allocate("gc-stale").unwrap().keep();
+
+        // Stands in for the live jail's Firecracker
+        let mut process = Command::new("/bin/sh")
+            .args(["-c", "sleep 10; true", "firecracker", "--id", "gc-live"])
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let options = GcOptions {
+            chroot_base: chroot_base.clone(),
+            min_age: Duration::from_secs(0),
+            namespaces: false,
+            users: Some(users.clone()),
+        };
+        let report = collect(&options).unwrap();
+        assert_eq!(report.jails, vec![stale.clone()]);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.users, vec![user]);
+        assert!(!stale.join("root").exists());
+        assert!(live.join("root").exists());
+
+        // The snapshot outlives the chroot, and the jail isn't collected again
+        assert_eq!(
+            fs::read_to_string(stale.join("snapshot/vmstate")).unwrap(),
+            "state"
+        );
+        assert_eq!(collect(&options).unwrap().jails, Vec::<PathBuf>::new());
+
+        // Recently-modified jails are kept
+        process.kill().unwrap();
+        process.wait().unwrap();
+        let report = collect(&GcOptions {
+            min_age: Duration::from_secs(3600),
+            ..options.clone()
+        })
+        .unwrap();
+        assert!(report.jails.is_empty());
+        assert_eq!(collect(&options).unwrap().jails, vec![live]);
+    }
+
+    #[test]
+    fn test_unescape_mount_point() {
+        assert_eq!(unescape_mount_point("/srv/my\\040jail"), "/srv/my jail");
+        assert_eq!(unescape_mount_point("/plain"), "/plain");
+    }
+}
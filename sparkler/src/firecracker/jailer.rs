@@ -5,15 +5,23 @@ use std::{
 };
 
 use nix::unistd::{Gid, Uid};
-use unshare::{Child, Command, Namespace};
+use serde::{Deserialize, Serialize};
+use unshare::{Child, Command, ExitStatus, Namespace, Signal};
 
 use crate::Error;
 
-// TODO: systemd-like dynamic users?
+pub mod cgroup;
+pub mod users;
+
+use self::cgroup::{host_cgroup_mode, CgroupVersion, ResourceLimits};
+use self::users::DynamicUser;
 
 const DEFAULT_JAILER: &str = "/usr/bin/jailer";
 const DEFAULT_FIRECRACKER: &str = "/usr/bin/firecracker";
-const DEFAULT_CHROOT_BASE: &str = "/srv/jailer";
+pub(crate) const DEFAULT_CHROOT_BASE: &str = "/srv/jailer";
+
+/// Longest jail ID that the jailer accepts
+const MAX_ID_LENGTH: usize = 64;
 
 /// Firecracker jail configuration.
 ///
@@ -31,7 +39,7 @@ pub struct Config<'a> {
     /// Unique microVM jail ID
     id: &'a str,
 
-    /// User to switch to when running Firecracker
+    /// User to switch to when running Firecracker. See [`users`] for giving each microVM its own user.
     user: Uid,
 
     /// Group to switch to when running Firecracker
@@ -42,7 +50,7 @@ pub struct Config<'a> {
     chroot_base: &'a Path,
 
     /// Network namespace to join before running Firecracker
-    #[builder(setter(strip_option))]
+    #[builder(setter(strip_option), default)]
     network_namespace: Option<&'a Path>,
 
     /// cgroup settings to apply to the Firecracker process. Keys are cgroup file names (like `cpuset.cpus`) and values are the file contents
@@ -50,6 +58,33 @@ pub struct Config<'a> {
     #[builder(default = "{ HashMap::new() }")]
     cgroup: HashMap<String, String>,
 
+    /// cgroup version for the jailer to use. If unset, the jailer uses cgroup v1.
+    #[builder(setter(strip_option), default)]
+    cgroup_version: Option<CgroupVersion>,
+
+    /// cgroup to create the microVM's cgroup under, relative to the cgroup root. If unset, the jailer uses the name of the Firecracker
+    /// binary.
+    #[builder(setter(strip_option), default)]
+    parent_cgroup: Option<&'a str>,
+
+    /// Resource limits to apply through the Firecracker process's cgroup, in addition to the raw [`cgroup`](ConfigBuilder::cgroup)
+    /// settings.
+    #[builder(setter(strip_option), default)]
+    resource_limits: Option<&'a ResourceLimits>,
+
+    /// Limits the jailer sets on the Firecracker process with `--resource-limit`. Unset limits keep the jailer's defaults.
+    #[builder(default)]
+    process_limits: ProcessLimits,
+
+    /// Seccomp filtering for Firecracker
+    #[builder(default)]
+    seccomp: Seccomp,
+
+    /// Whether the jailer should detach Firecracker from the calling process with `--daemonize`, so the microVM keeps running after
+    /// sparkler exits. Firecracker's standard input and output are redirected to `/dev/null`. See [`daemon`](super::daemon).
+    #[builder(default)]
+    daemonize: bool,
+
     /// Command-line arguments for Firecracker. Note that the jailer passes some additional arguments such as `--id`.
     #[builder(default = "{ Vec::new() }")]
     firecracker_args: Vec<OsString>,
@@ -58,7 +93,7 @@ pub struct Config<'a> {
 impl<'a> Config<'a> {
     /// Creates a new jailer configuration with the given microVM ID, user ID, and group ID. This uses the default paths for the jailer binary,
     /// Firecracker binary, and chroot base.
-    fn new(id: &'a str, user: Uid, group: Gid) -> Config<'a> {
+    pub fn new(id: &'a str, user: Uid, group: Gid) -> Config<'a> {
         Config {
             jailer_binary: Path::new(DEFAULT_JAILER),
             firecracker_binary: Path::new(DEFAULT_FIRECRACKER),
@@ -68,10 +103,36 @@ impl<'a> Config<'a> {
             chroot_base: Path::new(DEFAULT_CHROOT_BASE),
             network_namespace: None,
             cgroup: HashMap::new(),
+            cgroup_version: None,
+            parent_cgroup: None,
+            resource_limits: None,
+            process_limits: ProcessLimits::default(),
+            seccomp: Seccomp::default(),
+            daemonize: false,
             firecracker_args: Vec::new(),
         }
     }
 
+    /// Unique microVM jail ID
+    pub fn id(&self) -> &str {
+        self.id
+    }
+
+    /// User that Firecracker runs as inside the jail
+    pub fn user(&self) -> Uid {
+        self.user
+    }
+
+    /// Group that Firecracker runs as inside the jail
+    pub fn group(&self) -> Gid {
+        self.group
+    }
+
+    /// Whether the jailer detaches Firecracker from the calling process
+    pub fn daemonize(&self) -> bool {
+        self.daemonize
+    }
+
     /// Directory that the jailer will chroot into before running Firecracker.
     ///
     /// This takes the form `$chroot_base/$(basename $firecracker_binary)/$id`.
@@ -86,37 +147,663 @@ impl<'a> Config<'a> {
         path.push("root");
         path
     }
+
+    /// Path on the host to the Firecracker API socket inside the jail.
+    pub fn api_socket_path(&self) -> PathBuf {
+        self.chroot_path().join("run").join("firecracker.socket")
+    }
+
+    /// The jailer command line that [`spawn`] runs for this configuration.
+    pub fn invocation(&self) -> JailerInvocation {
+        JailerInvocation::new(self)
+    }
+
+    /// Checks that the cgroup settings are valid and usable on this host. This is done by [`spawn`], but can be called earlier to catch
+    /// problems before setting up the rest of the microVM.
+    pub fn check_cgroups(&self) -> Result<(), Error> {
+        let uses_cgroups = !self.cgroup.is_empty()
+            || self.cgroup_version.is_some()
+            || self.parent_cgroup.is_some()
+            || self.resource_limits.is_some();
+        if !uses_cgroups {
+            return Ok(());
+        }
+
+        if let Some(limits) = self.resource_limits {
+            limits.validate()?;
+        }
+
+        let version = self.cgroup_version.unwrap_or_default();
+        let mode = host_cgroup_mode()?;
+        if mode.supports(version) {
+            Ok(())
+        } else {
+            Err(Error::InvalidJailerConfig(format!(
+                "the jailer is configured for {}, but host cgroups are {}",
+                version, mode
+            )))
+        }
+    }
+}
+
+impl<'a> ConfigBuilder<'a> {
+    /// Runs Firecracker as the dynamically-allocated `user`, setting both the user and group.
+    pub fn dynamic_user(&mut self, user: &DynamicUser) -> &mut Self {
+        self.user(user.uid()).group(user.gid())
+    }
+}
+
+/// Process resource limits, which the jailer sets with `setrlimit` before running Firecracker. See the `setrlimit(2)` man page.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProcessLimits {
+    /// Largest file Firecracker may create, in bytes (`RLIMIT_FSIZE`). The jailer leaves this unlimited by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsize: Option<u64>,
+
+    /// Maximum number of open file descriptors (`RLIMIT_NOFILE`). The jailer defaults to 2048.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_file: Option<u64>,
+}
+
+impl ProcessLimits {
+    fn is_empty(&self) -> bool {
+        *self == ProcessLimits::default()
+    }
+}
+
+/// Seccomp filtering for Firecracker's threads.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Seccomp {
+    /// Use the filters built into Firecracker, which only allow the system calls it needs.
+    #[default]
+    Default,
+
+    /// Use the compiled filter at this path inside the jail, passed as `--seccomp-filter`. The file can be placed in the jail with
+    /// [`Stager::stage`](super::staging::Stager::stage).
+    Filter(PathBuf),
+
+    /// Disable seccomp filtering with `--no-seccomp`. This is only meant for debugging.
+    Disabled,
+}
+
+impl Seccomp {
+    fn is_default(&self) -> bool {
+        *self == Seccomp::Default
+    }
+}
+
+/// Owned version of [`Config`], which can be stored or sent between tasks. Use [`JailerConfig::config`] or [`JailerConfig::builder`] to get a
+/// `Config` for starting the jailer.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JailerConfig {
+    /// Path to the `jailer` executable
+    #[serde(default = "default_jailer")]
+    pub jailer_binary: PathBuf,
+
+    /// Path to the `firecracker` executable
+    #[serde(default = "default_firecracker")]
+    pub firecracker_binary: PathBuf,
+
+    /// Unique microVM jail ID. This may only contain ASCII letters, digits, and hyphens, and be at most 64 characters long.
+    pub id: String,
+
+    /// User ID to switch to when running Firecracker
+    pub user: u32,
+
+    /// Group ID to switch to when running Firecracker
+    pub group: u32,
+
+    /// Base directory to create chroot jails under
+    #[serde(default = "default_chroot_base")]
+    pub chroot_base: PathBuf,
+
+    /// Network namespace to join before running Firecracker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_namespace: Option<PathBuf>,
+
+    /// cgroup settings to apply to the Firecracker process, as in [`ConfigBuilder::cgroup`]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub cgroup: HashMap<String, String>,
+
+    /// cgroup version for the jailer to use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup_version: Option<CgroupVersion>,
+
+    /// cgroup to create the microVM's cgroup under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_cgroup: Option<String>,
+
+    /// Resource limits to apply through the Firecracker process's cgroup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_limits: Option<ResourceLimits>,
+
+    /// Limits the jailer sets on the Firecracker process
+    #[serde(default, skip_serializing_if = "ProcessLimits::is_empty")]
+    pub process_limits: ProcessLimits,
+
+    /// Seccomp filtering for Firecracker
+    #[serde(default, skip_serializing_if = "Seccomp::is_default")]
+    pub seccomp: Seccomp,
+
+    /// Whether to detach Firecracker from the calling process, as in [`ConfigBuilder::daemonize`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub daemonize: bool,
+
+    /// Command-line arguments for Firecracker
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub firecracker_args: Vec<String>,
+}
+
+fn default_jailer() -> PathBuf {
+    DEFAULT_JAILER.into()
+}
+
+fn default_firecracker() -> PathBuf {
+    DEFAULT_FIRECRACKER.into()
+}
+
+fn default_chroot_base() -> PathBuf {
+    DEFAULT_CHROOT_BASE.into()
+}
+
+impl JailerConfig {
+    /// Creates a new jailer configuration with the given microVM ID, user ID, and group ID, like [`Config::new`].
+    pub fn new<S: Into<String>>(id: S, user: Uid, group: Gid) -> JailerConfig {
+        JailerConfig {
+            jailer_binary: default_jailer(),
+            firecracker_binary: default_firecracker(),
+            id: id.into(),
+            user: user.as_raw(),
+            group: group.as_raw(),
+            chroot_base: default_chroot_base(),
+            network_namespace: None,
+            cgroup: HashMap::new(),
+            cgroup_version: None,
+            parent_cgroup: None,
+            resource_limits: None,
+            process_limits: ProcessLimits::default(),
+            seccomp: Seccomp::default(),
+            daemonize: false,
+            firecracker_args: Vec::new(),
+        }
+    }
+
+    /// Checks that the jailer will accept this configuration: the jail ID must be valid, paths must be absolute, the jailer and
+    /// Firecracker binaries must exist, and resource limits must be in range. Whether the host supports the cgroup settings is checked by
+    /// [`Config::check_cgroups`].
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_id(&self.id)?;
+        if let Some(limits) = &self.resource_limits {
+            limits.validate()?;
+        }
+
+        let mut paths = vec![
+            ("jailer binary", &self.jailer_binary),
+            ("Firecracker binary", &self.firecracker_binary),
+            ("chroot base", &self.chroot_base),
+        ];
+        if let Some(netns) = &self.network_namespace {
+            paths.push(("network namespace", netns));
+        }
+        if let Seccomp::Filter(filter) = &self.seccomp {
+            paths.push(("seccomp filter", filter));
+        }
+        for (name, path) in paths {
+            if !path.is_absolute() {
+                return Err(Error::InvalidJailerConfig(format!(
+                    "{} path {} is not absolute",
+                    name,
+                    path.display()
+                )));
+            }
+        }
+
+        for (name, path) in &[
+            ("jailer binary", &self.jailer_binary),
+            ("Firecracker binary", &self.firecracker_binary),
+        ] {
+            if !path.is_file() {
+                return Err(Error::InvalidJailerConfig(format!(
+                    "{} {} does not exist",
+                    name,
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a builder initialized with this configuration, for overriding individual settings.
+    pub fn builder(&self) -> ConfigBuilder<'_> {
+        let mut builder = ConfigBuilder::default();
+        builder
+            .jailer_binary(&self.jailer_binary)
+            .firecracker_binary(&self.firecracker_binary)
+            .id(&self.id)
+            .user(Uid::from_raw(self.user))
+            .group(Gid::from_raw(self.group))
+            .chroot_base(&self.chroot_base)
+            .cgroup(self.cgroup.clone())
+            .process_limits(self.process_limits)
+            .seccomp(self.seccomp.clone())
+            .daemonize(self.daemonize)
+            .firecracker_args(self.firecracker_args.iter().map(OsString::from).collect());
+        if let Some(netns) = &self.network_namespace {
+            builder.network_namespace(netns);
+        }
+        if let Some(version) = self.cgroup_version {
+            builder.cgroup_version(version);
+        }
+        if let Some(parent) = &self.parent_cgroup {
+            builder.parent_cgroup(parent);
+        }
+        if let Some(limits) = &self.resource_limits {
+            builder.resource_limits(limits);
+        }
+        builder
+    }
+
+    /// Returns a borrowed [`Config`] with these settings.
+    pub fn config(&self) -> Config<'_> {
+        self.builder()
+            .build()
+            .expect("jailer configuration is incomplete")
+    }
+}
+
+/// Checks a jail ID against the jailer's requirements.
+fn validate_id(id: &str) -> Result<(), Error> {
+    if id.is_empty() || id.len() > MAX_ID_LENGTH {
+        return Err(Error::InvalidJailerConfig(format!(
+            "jail ID {:?} must be between 1 and {} characters long",
+            id, MAX_ID_LENGTH
+        )));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(Error::InvalidJailerConfig(format!(
+            "jail ID {:?} may only contain ASCII letters, digits, and hyphens",
+            id
+        )));
+    }
+    Ok(())
+}
+
+/// The jailer command line for a [`Config`], which [`spawn`] runs. This can be used to log or check the command before starting it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JailerInvocation {
+    program: PathBuf,
+    args: Vec<OsString>,
+    /// Index of the `--` separating the jailer's arguments from Firecracker's, if there is one
+    separator: Option<usize>,
+    daemonize: bool,
 }
 
-fn build_command(config: &Config<'_>) -> Command {
-    // Use `unshare` for starting the jailer, since it handles the nuances of safely `clone()`ing from Rust. The alternative would be doing the
-    // clone(CLONE_NEWPID) -> exec dance ourselves, while making sure not to accidentally deadlock or break things.
-    let mut command = Command::new(config.jailer_binary);
+impl JailerInvocation {
+    /// Builds the jailer command line for `config`.
+    pub fn new(config: &Config<'_>) -> JailerInvocation {
+        let mut args: Vec<OsString> = Vec::new();
+        let mut arg = |name: &str, value: OsString| {
+            args.push(name.into());
+            args.push(value);
+        };
 
-    command.arg("--id").arg(config.id);
-    command.arg("--exec-file").arg(config.firecracker_binary);
-    command.arg("--uid").arg(config.user.to_string());
-    command.arg("--gid").arg(config.group.to_string());
-    command.arg("--chroot-base-dir").arg(config.chroot_base);
+        arg("--id", config.id.into());
+        arg("--exec-file", config.firecracker_binary.into());
+        arg("--uid", config.user.to_string().into());
+        arg("--gid", config.group.to_string().into());
+        arg("--chroot-base-dir", config.chroot_base.into());
 
-    for (file, value) in config.cgroup.iter() {
-        command.arg("--cgroup").arg(format!("{}={}", file, value));
+        // Sorted so the command line is the same every time
+        let mut cgroup: Vec<_> = config.cgroup.iter().collect();
+        cgroup.sort();
+        for (file, value) in cgroup {
+            arg("--cgroup", format!("{}={}", file, value).into());
+        }
+        if let Some(limits) = config.resource_limits {
+            for (file, value) in limits.cgroup_settings(config.cgroup_version.unwrap_or_default()) {
+                arg("--cgroup", format!("{}={}", file, value).into());
+            }
+        }
+        if let Some(version) = config.cgroup_version {
+            arg("--cgroup-version", version.as_arg().into());
+        }
+        if let Some(parent) = config.parent_cgroup {
+            arg("--parent-cgroup", parent.into());
+        }
+
+        if let Some(fsize) = config.process_limits.fsize {
+            arg("--resource-limit", format!("fsize={}", fsize).into());
+        }
+        if let Some(no_file) = config.process_limits.no_file {
+            arg("--resource-limit", format!("no-file={}", no_file).into());
+        }
+
+        if let Some(netns) = config.network_namespace {
+            arg("--netns", netns.into());
+        }
+
+        if config.daemonize {
+            args.push("--daemonize".into());
+        }
+
+        let mut firecracker_args: Vec<OsString> = match &config.seccomp {
+            Seccomp::Default => Vec::new(),
+            Seccomp::Filter(filter) => vec!["--seccomp-filter".into(), filter.into()],
+            Seccomp::Disabled => vec!["--no-seccomp".into()],
+        };
+        firecracker_args.extend(config.firecracker_args.iter().cloned());
+        let separator = if firecracker_args.is_empty() {
+            None
+        } else {
+            args.push("--".into());
+            let separator = args.len() - 1;
+            args.extend(firecracker_args);
+            Some(separator)
+        };
+
+        JailerInvocation {
+            program: config.jailer_binary.to_path_buf(),
+            args,
+            separator,
+            daemonize: config.daemonize,
+        }
+    }
+
+    /// Path to the jailer executable
+    pub fn program(&self) -> &Path {
+        &self.program
     }
 
-    if let Some(netns) = config.network_namespace {
-        command.arg("--netns").arg(netns);
+    /// All arguments, not including the program name
+    pub fn args(&self) -> &[OsString] {
+        &self.args
     }
 
-    if !config.firecracker_args.is_empty() {
-        command.arg("--");
-        command.args(&config.firecracker_args);
+    /// Arguments for the jailer itself, before the `--`
+    pub fn jailer_args(&self) -> &[OsString] {
+        &self.args[..self.separator.unwrap_or(self.args.len())]
     }
 
-    command.unshare(&[Namespace::Pid]);
+    /// Arguments the jailer passes through to Firecracker, after the `--`
+    pub fn firecracker_args(&self) -> &[OsString] {
+        match self.separator {
+            Some(separator) => &self.args[separator + 1..],
+            None => &[],
+        }
+    }
+
+    /// Renders the command, with the jailer in a new PID namespace.
+    fn command(&self) -> Command {
+        // Use `unshare` for starting the jailer, since it handles the nuances of safely `clone()`ing from Rust. The alternative would be
+        // doing the clone(CLONE_NEWPID) -> exec dance ourselves, while making sure not to accidentally deadlock or break things.
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+
+        if self.daemonize {
+            // By default, `unshare` kills the child when the parent exits
+            command.allow_daemonize();
+        }
+        command.unshare(&[Namespace::Pid]);
 
-    command
+        command
+    }
+}
+
+pub(crate) fn build_command(config: &Config<'_>) -> Command {
+    JailerInvocation::new(config).command()
 }
 
 pub fn spawn(config: &Config<'_>) -> Result<Child, Error> {
+    config.check_cgroups()?;
     build_command(config).spawn().map_err(Error::Jailer)
 }
+
+/// Checks whether a jailer process has exited, without waiting. The process is not reaped, so [`Child::wait`] can still be used afterwards
+/// to collect it.
+pub fn peek_exit_status(child: &Child) -> Result<Option<ExitStatus>, Error> {
+    use nix::libc;
+
+    // SAFETY: waitid only writes to `info`, and a zeroed siginfo_t is valid
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            child.pid() as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    if result == -1 {
+        return Err(Error::System {
+            context: format!("could not check status of jailer process {}", child.pid()),
+            error: nix::Error::last(),
+        });
+    }
+
+    // With WNOHANG, si_pid is left as 0 if the process is still running
+    let (pid, status) = unsafe { (info.si_pid(), info.si_status()) };
+    if pid == 0 {
+        return Ok(None);
+    }
+
+    let exit_status = match info.si_code {
+        libc::CLD_EXITED => ExitStatus::Exited(status as i8),
+        code => ExitStatus::Signaled(
+            Signal::from_c_int(status).unwrap_or(Signal::SIGKILL),
+            code == libc::CLD_DUMPED,
+        ),
+    };
+    Ok(Some(exit_status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jailer_config() {
+        let config: JailerConfig = serde_json::from_value(serde_json::json!({
+            "id": "vm-1",
+            "user": 1000,
+            "group": 1000,
+            "firecracker-args": ["--level", "Debug"]
+        }))
+        .unwrap();
+        assert_eq!(config.jailer_binary, Path::new(DEFAULT_JAILER));
+        assert_eq!(
+            config.config().chroot_path(),
+            Path::new("/srv/jailer/firecracker/vm-1/root")
+        );
+
+        let invalid = |config: JailerConfig| match config.validate() {
+            Err(Error::InvalidJailerConfig(reason)) => reason,
+            other => panic!("expected invalid configuration, got {:?}", other),
+        };
+        let valid = JailerConfig {
+            jailer_binary: "/bin/sh".into(),
+            firecracker_binary: "/bin/sh".into(),
+            ..config
+        };
+        valid.validate().unwrap();
+
+        assert!(invalid(JailerConfig {
+            id: "a".repeat(65),
+            ..valid.clone()
+        })
+        .contains("between 1 and 64"));
+        assert!(invalid(JailerConfig {
+            id: "vm_1".into(),
+            ..valid.clone()
+        })
+        .contains("hyphens"));
+        assert!(invalid(JailerConfig {
+            chroot_base: "jails".into(),
+            ..valid.clone()
+        })
+        .contains("not absolute"));
+        assert!(invalid(JailerConfig {
+            firecracker_binary: "/nonexistent/firecracker".into(),
+            ..valid
+        })
+        .contains("does not exist"));
+    }
+
+    fn strings(args: &[OsString]) -> Vec<String> {
+        args.iter()
+            .map(|arg| arg.clone().into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_invocation() {
+        let limits = ResourceLimits {
+            pids_max: Some(64),
+            ..ResourceLimits::default()
+        };
+        let mut cgroup = HashMap::new();
+        cgroup.insert("cpuset.mems".to_string(), "0".to_string());
+        cgroup.insert("cpuset.cpus".to_string(), "2-3".to_string());
+        let config = ConfigBuilder::default()
+            .jailer_binary(Path::new("/opt/fc/jailer"))
+            .firecracker_binary(Path::new("/opt/fc/firecracker-v1.4"))
+            .id("vm-1")
+            .user(Uid::from_raw(61184))
+            .group(Gid::from_raw(61184))
+            .chroot_base(Path::new("/var/lib/jails"))
+            .network_namespace(Path::new("/var/run/netns/vm-1"))
+            .cgroup(cgroup)
+            .cgroup_version(CgroupVersion::V2)
+            .parent_cgroup("sparkler")
+            .resource_limits(&limits)
+            .firecracker_args(vec!["--config-file".into(), "/vm_config.json".into()])
+            .build()
+            .unwrap();
+
+        let invocation = config.invocation();
+        assert_eq!(invocation.program(), Path::new("/opt/fc/jailer"));
+        assert_eq!(
+            strings(invocation.jailer_args()),
+            vec![
+                "--id",
+                "vm-1",
+                "--exec-file",
+                "/opt/fc/firecracker-v1.4",
+                "--uid",
+                "61184",
+                "--gid",
+                "61184",
+                "--chroot-base-dir",
+                "/var/lib/jails",
+                "--cgroup",
+                "cpuset.cpus=2-3",
+                "--cgroup",
+                "cpuset.mems=0",
+                "--cgroup",
+                "pids.max=64",
+                "--cgroup-version",
+                "2",
+                "--parent-cgroup",
+                "sparkler",
+                "--netns",
+                "/var/run/netns/vm-1",
+            ]
+        );
+        // Firecracker's arguments come after `--`, so the jailer doesn't interpret them
+        assert_eq!(
+            strings(invocation.firecracker_args()),
+            vec!["--config-file", "/vm_config.json"]
+        );
+        assert_eq!(
+            invocation.args()[invocation.jailer_args().len()],
+            OsString::from("--")
+        );
+        assert_eq!(
+            invocation.args().len(),
+            invocation.jailer_args().len() + 1 + invocation.firecracker_args().len()
+        );
+
+        // The jail is named after the Firecracker binary and the ID
+        assert_eq!(
+            config.chroot_path(),
+            Path::new("/var/lib/jails/firecracker-v1.4/vm-1/root")
+        );
+        assert_eq!(
+            config.api_socket_path(),
+            Path::new("/var/lib/jails/firecracker-v1.4/vm-1/root/run/firecracker.socket")
+        );
+
+        // Without Firecracker arguments, there is no `--`
+        let config = Config::new("vm-2", Uid::from_raw(1000), Gid::from_raw(1000));
+        let invocation = config.invocation();
+        assert!(invocation.firecracker_args().is_empty());
+        assert_eq!(invocation.jailer_args(), invocation.args());
+        assert!(!invocation.args().contains(&OsString::from("--")));
+        assert_eq!(
+            config.chroot_path(),
+            Path::new("/srv/jailer/firecracker/vm-2/root")
+        );
+    }
+
+    #[test]
+    fn test_hardening_args() {
+        let args = |config: &JailerConfig| strings(config.config().invocation().args());
+        fn base(id: &str) -> Vec<&str> {
+            vec![
+                "--id",
+                id,
+                "--exec-file",
+                "/usr/bin/firecracker",
+                "--uid",
+                "1000",
+                "--gid",
+                "1000",
+                "--chroot-base-dir",
+                "/srv/jailer",
+            ]
+        }
+
+        // Tight limits and a custom filter, as in CI
+        let mut hardened = JailerConfig::new("ci", Uid::from_raw(1000), Gid::from_raw(1000));
+        hardened.process_limits = ProcessLimits {
+            fsize: Some(1 << 30),
+            no_file: Some(256),
+        };
+        hardened.seccomp = Seccomp::Filter("/seccomp.bpf".into());
+        hardened.firecracker_args = vec!["--level".into(), "Warning".into()];
+        let mut expected = base("ci");
+        expected.extend(&[
+            "--resource-limit",
+            "fsize=1073741824",
+            "--resource-limit",
+            "no-file=256",
+            "--",
+            "--seccomp-filter",
+            "/seccomp.bpf",
+            "--level",
+            "Warning",
+        ]);
+        assert_eq!(args(&hardened), expected);
+
+        // No seccomp, for debugging locally
+        let mut debug = JailerConfig::new("debug", Uid::from_raw(1000), Gid::from_raw(1000));
+        debug.seccomp = Seccomp::Disabled;
+        let mut expected = base("debug");
+        expected.extend(&["--", "--no-seccomp"]);
+        assert_eq!(args(&debug), expected);
+
+        // The defaults add nothing
+        let default = JailerConfig::new("default", Uid::from_raw(1000), Gid::from_raw(1000));
+        assert_eq!(args(&default), base("default"));
+
+        let serialized = serde_json::to_value(&debug).unwrap();
+        assert_eq!(serialized["seccomp"], "disabled");
+        assert_eq!(
+            serde_json::from_value::<JailerConfig>(serialized).unwrap(),
+            debug
+        );
+    }
+}
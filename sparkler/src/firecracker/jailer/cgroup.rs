@@ -0,0 +1,306 @@
+//! Typed cgroup settings for the jailer
+//!
+//! The jailer places Firecracker in a cgroup and writes `--cgroup <file>=<value>` settings into it. The file names differ between cgroup v1
+//! and the unified v2 hierarchy, so [`ResourceLimits`] describes limits independently of the version and translates them for whichever
+//! [`CgroupVersion`] the jailer is using. [`host_cgroup_mode`] detects how the host's cgroups are mounted, so a jailer configured for the
+//! wrong version fails before it's started instead of partway through setting up the jail.
+
+use std::fmt;
+use std::path::Path;
+
+use nix::sys::statfs::{statfs, CGROUP2_SUPER_MAGIC, TMPFS_MAGIC};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Where cgroup filesystems are mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Where systemd mounts the v2 hierarchy on hybrid hosts
+const UNIFIED_ROOT: &str = "/sys/fs/cgroup/unified";
+
+/// Range of v2 `cpu.weight` and `io.weight` values
+const WEIGHT_RANGE: (u64, u64) = (1, 10000);
+
+/// cgroup version for the jailer to use, passed as `--cgroup-version`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CgroupVersion {
+    #[serde(rename = "1")]
+    V1,
+    #[serde(rename = "2")]
+    V2,
+}
+
+impl CgroupVersion {
+    /// Value for the jailer's `--cgroup-version` flag
+    pub fn as_arg(self) -> &'static str {
+        match self {
+            CgroupVersion::V1 => "1",
+            CgroupVersion::V2 => "2",
+        }
+    }
+}
+
+impl fmt::Display for CgroupVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cgroup v{}", self.as_arg())
+    }
+}
+
+impl Default for CgroupVersion {
+    /// The jailer uses cgroup v1 unless told otherwise.
+    fn default() -> CgroupVersion {
+        CgroupVersion::V1
+    }
+}
+
+/// How cgroups are mounted on the host.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CgroupMode {
+    /// Only cgroup v1 hierarchies are mounted.
+    Legacy,
+    /// cgroup v1 hierarchies are mounted, along with a v2 hierarchy that has no controllers (the systemd "hybrid" layout).
+    Hybrid,
+    /// Only the v2 hierarchy is mounted.
+    Unified,
+}
+
+impl CgroupMode {
+    /// Whether the jailer can apply limits using cgroup `version` on a host in this mode. Hybrid hosts have a v2 hierarchy, but all
+    /// controllers are attached to the v1 hierarchies, so only v1 is usable.
+    pub fn supports(self, version: CgroupVersion) -> bool {
+        match self {
+            CgroupMode::Legacy | CgroupMode::Hybrid => version == CgroupVersion::V1,
+            CgroupMode::Unified => version == CgroupVersion::V2,
+        }
+    }
+}
+
+impl fmt::Display for CgroupMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CgroupMode::Legacy => "legacy (cgroup v1)",
+            CgroupMode::Hybrid => "hybrid (cgroup v1 controllers)",
+            CgroupMode::Unified => "unified (cgroup v2)",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Detects how cgroups are mounted on the host.
+pub fn host_cgroup_mode() -> Result<CgroupMode, Error> {
+    let root = statfs(CGROUP_ROOT).map_err(|error| Error::System {
+        context: format!("could not inspect {}", CGROUP_ROOT),
+        error,
+    })?;
+
+    let fs_type = root.filesystem_type();
+    if fs_type == CGROUP2_SUPER_MAGIC {
+        Ok(CgroupMode::Unified)
+    } else if fs_type == TMPFS_MAGIC {
+        let hybrid = Path::new(UNIFIED_ROOT).exists()
+            && statfs(UNIFIED_ROOT)
+                .map(|unified| unified.filesystem_type() == CGROUP2_SUPER_MAGIC)
+                .unwrap_or(false);
+        Ok(if hybrid {
+            CgroupMode::Hybrid
+        } else {
+            CgroupMode::Legacy
+        })
+    } else {
+        Err(Error::InvalidJailerConfig(format!(
+            "{} is not a cgroup filesystem",
+            CGROUP_ROOT
+        )))
+    }
+}
+
+/// CPU bandwidth limit: the cgroup may use `quota_us` of CPU time in every `period_us`. For example, a quota of 200000 with a period of
+/// 100000 allows two full CPUs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CpuQuota {
+    pub quota_us: u64,
+    pub period_us: u64,
+}
+
+/// Resource limits for the Firecracker process, applied through its cgroup.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceLimits {
+    /// CPU bandwidth limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<CpuQuota>,
+
+    /// Relative CPU weight, from 1 to 10000, with a default of 100. On cgroup v1 this is converted to `cpu.shares`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_weight: Option<u64>,
+
+    /// CPUs the process may run on, in cpuset list format (like `0-3,8`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_cpus: Option<String>,
+
+    /// NUMA nodes the process may allocate memory on, in cpuset list format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_mems: Option<String>,
+
+    /// Hard memory limit, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<u64>,
+
+    /// Memory usage above which the process is throttled and reclaimed from, in bytes. On cgroup v1 this is the soft limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_high: Option<u64>,
+
+    /// Relative IO weight, from 1 to 10000, with a default of 100. On cgroup v1 this is converted to `blkio.weight`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_weight: Option<u64>,
+
+    /// Maximum number of processes and threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Checks that all limits are in range.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, weight) in &[("CPU", self.cpu_weight), ("IO", self.io_weight)] {
+            if let Some(weight) = *weight {
+                if weight < WEIGHT_RANGE.0 || weight > WEIGHT_RANGE.1 {
+                    return Err(Error::InvalidJailerConfig(format!(
+                        "{} weight {} is not between {} and {}",
+                        name, weight, WEIGHT_RANGE.0, WEIGHT_RANGE.1
+                    )));
+                }
+            }
+        }
+
+        if let Some(quota) = self.cpu_quota {
+            if quota.quota_us == 0 || quota.period_us == 0 {
+                return Err(Error::InvalidJailerConfig(
+                    "CPU quota and period must be positive".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Translates these limits into `--cgroup` settings for cgroup `version`, as pairs of file name and value.
+    pub fn cgroup_settings(&self, version: CgroupVersion) -> Vec<(String, String)> {
+        let mut settings = Vec::new();
+        let mut set = |file: &str, value: String| settings.push((file.to_string(), value));
+
+        match version {
+            CgroupVersion::V1 => {
+                if let Some(quota) = self.cpu_quota {
+                    set("cpu.cfs_quota_us", quota.quota_us.to_string());
+                    set("cpu.cfs_period_us", quota.period_us.to_string());
+                }
+                if let Some(weight) = self.cpu_weight {
+                    // cpu.weight defaults to 100, and cpu.shares to 1024
+                    set("cpu.shares", (weight * 1024 / 100).max(2).to_string());
+                }
+                if let Some(max) = self.memory_max {
+                    set("memory.limit_in_bytes", max.to_string());
+                }
+                if let Some(high) = self.memory_high {
+                    set("memory.soft_limit_in_bytes", high.to_string());
+                }
+                if let Some(weight) = self.io_weight {
+                    // blkio.weight ranges from 10 to 1000, and defaults to 500
+                    set("blkio.weight", (weight * 5).clamp(10, 1000).to_string());
+                }
+            }
+            CgroupVersion::V2 => {
+                if let Some(quota) = self.cpu_quota {
+                    set("cpu.max", format!("{} {}", quota.quota_us, quota.period_us));
+                }
+                if let Some(weight) = self.cpu_weight {
+                    set("cpu.weight", weight.to_string());
+                }
+                if let Some(max) = self.memory_max {
+                    set("memory.max", max.to_string());
+                }
+                if let Some(high) = self.memory_high {
+                    set("memory.high", high.to_string());
+                }
+                if let Some(weight) = self.io_weight {
+                    set("io.weight", weight.to_string());
+                }
+            }
+        }
+
+        // These are the same in both versions
+        if let Some(cpus) = &self.cpuset_cpus {
+            set("cpuset.cpus", cpus.clone());
+        }
+        if let Some(mems) = &self.cpuset_mems {
+            set("cpuset.mems", mems.clone());
+        }
+        if let Some(max) = self.pids_max {
+            set("pids.max", max.to_string());
+        }
+
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup_settings() {
+        let limits = ResourceLimits {
+            cpu_quota: Some(CpuQuota {
+                quota_us: 50000,
+                period_us: 100000,
+            }),
+            cpu_weight: Some(200),
+            cpuset_cpus: Some("0-1".into()),
+            memory_max: Some(512 << 20),
+            io_weight: Some(100),
+            pids_max: Some(64),
+            ..ResourceLimits::default()
+        };
+        limits.validate().unwrap();
+
+        let settings = |version| {
+            limits
+                .cgroup_settings(version)
+                .into_iter()
+                .map(|(file, value)| format!("{}={}", file, value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            settings(CgroupVersion::V1),
+            vec![
+                "cpu.cfs_quota_us=50000",
+                "cpu.cfs_period_us=100000",
+                "cpu.shares=2048",
+                "memory.limit_in_bytes=536870912",
+                "blkio.weight=500",
+                "cpuset.cpus=0-1",
+                "pids.max=64",
+            ]
+        );
+        assert_eq!(
+            settings(CgroupVersion::V2),
+            vec![
+                "cpu.max=50000 100000",
+                "cpu.weight=200",
+                "memory.max=536870912",
+                "io.weight=100",
+                "cpuset.cpus=0-1",
+                "pids.max=64",
+            ]
+        );
+
+        assert!(ResourceLimits {
+            io_weight: Some(0),
+            ..ResourceLimits::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,291 @@
+//! Dynamic per-VM users
+//!
+//! Running every microVM as the same user means that a compromised Firecracker process could interfere with the others. Instead, like
+//! systemd's `DynamicUser=`, each VM can be given its own UID and GID from a reserved range. Allocations are recorded in a JSON state file.
+//! Updates hold a [`FileLock`] on a lock file next to it while they read and rewrite the state, so that concurrent sparkler processes never
+//! hand out the same ID, and replace the state file atomically, so that it can be read without the lock.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{Gid, Group, Uid, User};
+use tracing::debug;
+
+use crate::util::FileLock;
+use crate::Error;
+
+/// Default range of IDs to allocate from. This is the same range systemd uses for dynamic users.
+pub const DEFAULT_RANGE: Range<u32> = 61184..65520;
+
+/// Default allocation state file
+pub const DEFAULT_STATE_PATH: &str = "/run/sparkler/users.json";
+
+/// Allocates UIDs and GIDs to microVMs. The same number is used for both the UID and the GID.
+#[derive(Clone, Debug)]
+pub struct UserAllocator {
+    state_path: PathBuf,
+    range: Range<u32>,
+}
+
+/// A UID and GID allocated to a microVM. The IDs are released when this is dropped, unless [`DynamicUser::keep`] is used.
+#[derive(Debug)]
+pub struct DynamicUser {
+    allocator: UserAllocator,
+    id: u32,
+    release_on_drop: bool,
+}
+
+impl UserAllocator {
+    /// Creates an allocator that hands out IDs from `range`, recording them in the file at `state_path`. All allocators sharing a range
+    /// must use the same state file.
+    pub fn new<P: Into<PathBuf>>(state_path: P, range: Range<u32>) -> UserAllocator {
+        UserAllocator {
+            state_path: state_path.into(),
+            range,
+        }
+    }
+
+    /// Path to the allocation state file
+    pub fn state_path(&self) -> &Path {
+        &self.state_path
+    }
+
+    /// Allocates an unused ID to `owner`, which is recorded for diagnostics and for [`UserAllocator::release_owner`]. IDs belonging to
+    /// existing users or groups on the host are skipped.
+    pub fn allocate(&self, owner: &str) -> Result<DynamicUser, Error> {
+        let range = self.range.clone();
+        let id = self.update(|allocations| {
+            for id in range {
+                if allocations.contains_key(&id) || host_id_exists(id)? {
+                    continue;
+                }
+                allocations.insert(id, owner.to_string());
+                return Ok(Some(id));
+            }
+            Ok(None)
+        })?;
+
+        match id {
+            Some(id) => {
+                debug!("Allocated user {} to {}", id, owner);
+                Ok(DynamicUser {
+                    allocator: self.clone(),
+                    id,
+                    release_on_drop: true,
+                })
+            }
+            None => Err(Error::NoFreeUsers {
+                start: self.range.start,
+                end: self.range.end,
+            }),
+        }
+    }
+
+    /// Returns a previously-allocated ID, such as one saved with [`DynamicUser::keep`]. The ID will be released when the returned
+    /// `DynamicUser` is dropped.
+    pub fn adopt(&self, id: u32) -> DynamicUser {
+        DynamicUser {
+            allocator: self.clone(),
+            id,
+            release_on_drop: true,
+        }
+    }
+
+    /// Releases `id`, so it can be allocated again.
+    pub fn release(&self, id: u32) -> Result<(), Error> {
+        self.update(|allocations| {
+            allocations.remove(&id);
+            Ok(())
+        })?;
+        debug!("Released user {}", id);
+        Ok(())
+    }
+
+    /// Releases every ID allocated to `owner`. This is useful for cleaning up after a sparkler process that exited without releasing its
+    /// IDs.
+    pub fn release_owner(&self, owner: &str) -> Result<Vec<u32>, Error> {
+        self.update(|allocations| {
+            let released: Vec<u32> = allocations
+                .iter()
+                .filter(|(_, allocation_owner)| *allocation_owner == owner)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in &released {
+                allocations.remove(id);
+            }
+            Ok(released)
+        })
+    }
+
+    /// Returns all current allocations, mapping each ID to its owner.
+    pub fn allocations(&self) -> Result<BTreeMap<u32, String>, Error> {
+        read_allocations(&self.state_path).map_err(|error| Error::Io {
+            context: format!(
+                "could not read user allocations from {}",
+                self.state_path.display()
+            ),
+            error,
+        })
+    }
+
+    /// Takes the lock and applies `f` to the allocations in the state file, replacing it if they changed.
+    fn update<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut BTreeMap<u32, String>) -> Result<T, Error>,
+    {
+        let io_error = |error: io::Error| Error::Io {
+            context: format!(
+                "could not update user allocations in {}",
+                self.state_path.display()
+            ),
+            error,
+        };
+
+        if let Some(parent) = self.state_path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        // The state file is replaced on every update, so lock a separate file that stays put
+        let lock_path = self.state_path.with_extension("lock");
+        let lock_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(io_error)?;
+        let _lock = FileLock::new(&lock_file).map_err(|error| Error::System {
+            context: format!("could not lock {}", lock_path.display()),
+            error,
+        })?;
+
+        let original = read_allocations(&self.state_path).map_err(io_error)?;
+        let mut allocations = original.clone();
+        let result = f(&mut allocations)?;
+        if allocations != original {
+            write_allocations(&self.state_path, &allocations).map_err(io_error)?;
+        }
+        Ok(result)
+    }
+}
+
+impl Default for UserAllocator {
+    /// Allocates from [`DEFAULT_RANGE`], with state in [`DEFAULT_STATE_PATH`].
+    fn default() -> UserAllocator {
+        UserAllocator::new(DEFAULT_STATE_PATH, DEFAULT_RANGE)
+    }
+}
+
+impl DynamicUser {
+    /// The allocated ID
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The allocated ID, as a user ID
+    pub fn uid(&self) -> Uid {
+        Uid::from_raw(self.id)
+    }
+
+    /// The allocated ID, as a group ID
+    pub fn gid(&self) -> Gid {
+        Gid::from_raw(self.id)
+    }
+
+    /// Releases the ID now, reporting any error.
+    pub fn release(mut self) -> Result<(), Error> {
+        self.release_on_drop = false;
+        self.allocator.release(self.id)
+    }
+
+    /// Keeps the ID allocated after this is dropped, such as when the VM outlives this process. Returns the ID, which can be passed to
+    /// [`UserAllocator::adopt`] later.
+    pub fn keep(mut self) -> u32 {
+        self.release_on_drop = false;
+        self.id
+    }
+}
+
+impl Drop for DynamicUser {
+    fn drop(&mut self) {
+        if self.release_on_drop {
+            if let Err(err) = self.allocator.release(self.id) {
+                eprintln!("Releasing user {} failed: {}", self.id, err);
+            }
+        }
+    }
+}
+
+/// Whether a user or group with the numeric ID `id` already exists on the host.
+fn host_id_exists(id: u32) -> Result<bool, Error> {
+    let user = User::from_uid(Uid::from_raw(id)).map_err(|error| Error::System {
+        context: format!("could not look up user {}", id),
+        error,
+    })?;
+    let group = Group::from_gid(Gid::from_raw(id)).map_err(|error| Error::System {
+        context: format!("could not look up group {}", id),
+        error,
+    })?;
+    Ok(user.is_some() || group.is_some())
+}
+
+/// Reads the state file at `path`. A missing or empty file has no allocations.
+fn read_allocations(path: &Path) -> io::Result<BTreeMap<u32, String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err),
+    };
+    if contents.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Writes the state file at `path`, replacing it atomically. The caller must hold the lock.
+fn write_allocations(path: &Path, allocations: &BTreeMap<u32, String>) -> io::Result<()> {
+    let temp_path = path.with_extension("json.tmp");
+    let contents = serde_json::to_vec_pretty(allocations).expect("malformed user allocations");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&contents)?;
+    file.sync_data()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_allocate() {
+        let temp = TempDir::new("users");
+        let state_path = temp.path().join("users.json");
+        // Two allocators sharing a state file, as if in separate processes
+        let first = UserAllocator::new(&state_path, 61184..61186);
+        let second = UserAllocator::new(&state_path, 61184..61186);
+
+        let a = first.allocate("vm-a").unwrap();
+        let b = second.allocate("vm-b").unwrap();
+        assert_ne!(a.id(), b.id());
+        assert!(matches!(
+            first.allocate("vm-c"),
+            Err(Error::NoFreeUsers { .. })
+        ));
+
+        let a_id = a.id();
+        drop(a);
+        let c = second.allocate("vm-c").unwrap();
+        assert_eq!(c.id(), a_id);
+
+        let kept = c.keep();
+        drop(b);
+        assert_eq!(
+            first.allocations().unwrap(),
+            vec![(kept, "vm-c".to_string())].into_iter().collect()
+        );
+        assert_eq!(first.release_owner("vm-c").unwrap(), vec![kept]);
+        assert!(first.allocations().unwrap().is_empty());
+    }
+}
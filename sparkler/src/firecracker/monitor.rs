@@ -0,0 +1,298 @@
+//! Reading Firecracker's log and metrics output
+//!
+//! Firecracker writes human-readable logs and newline-delimited JSON metrics to paths configured through the API. Here, those paths are named
+//! pipes (FIFOs) inside the jail, which are read on a background thread and exposed as async [`Stream`]s.
+//!
+//! Metrics are flushed periodically (every 60 seconds) and whenever [`ActionType::FlushMetrics`](super::api::ActionType::FlushMetrics) is
+//! performed. Each line read from the metrics FIFO holds the counters accumulated since the previous flush.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_core::Stream;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{chown, mkfifo};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::api::{Client, LogLevel, Logger, Metrics};
+use super::jailer::Config;
+use crate::Error;
+
+/// Path of the log FIFO inside the jail
+const LOG_FIFO: &str = "/logs.fifo";
+
+/// Path of the metrics FIFO inside the jail
+const METRICS_FIFO: &str = "/metrics.fifo";
+
+/// Number of unread lines to buffer before the reader thread waits for the consumer.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Stream of lines read from a FIFO, parsed into `T`.
+pub struct FifoStream<T> {
+    receiver: mpsc::Receiver<Result<T, Error>>,
+}
+
+/// Stream of human-readable log lines from Firecracker
+pub type LogStream = FifoStream<String>;
+
+/// Stream of metrics flushed by Firecracker
+pub type MetricsStream = FifoStream<MetricsReport>;
+
+impl<T> Stream for FifoStream<T> {
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Metrics flushed by Firecracker. Only commonly-used groups are parsed; everything else (including per-device metrics such as
+/// `block_rootfs` or `net_eth0`) is kept in [`MetricsReport::other`].
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct MetricsReport {
+    /// Time the metrics were flushed, in milliseconds since the Unix epoch
+    pub utc_timestamp_ms: u64,
+
+    /// Metrics related to the API server
+    #[serde(default)]
+    pub api_server: ApiServerMetrics,
+
+    /// Metrics aggregated across all block devices
+    #[serde(default)]
+    pub block: BlockDeviceMetrics,
+
+    /// Metrics aggregated across all network devices
+    #[serde(default)]
+    pub net: NetDeviceMetrics,
+
+    /// Metrics aggregated across all vCPUs
+    #[serde(default)]
+    pub vcpu: VcpuMetrics,
+
+    /// Metrics related to the VMM itself
+    #[serde(default)]
+    pub vmm: VmmMetrics,
+
+    /// All other metric groups
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Default)]
+#[serde(default)]
+pub struct ApiServerMetrics {
+    /// Time from process start until the API server was ready, in microseconds
+    pub process_startup_time_us: u64,
+    /// CPU time from process start until the API server was ready, in microseconds
+    pub process_startup_time_cpu_us: u64,
+    /// Number of failures sending synchronous requests to the VMM
+    pub sync_response_fails: u64,
+    /// Number of timeouts waiting on the VMM for synchronous requests
+    pub sync_vmm_send_timeout_count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Default)]
+#[serde(default)]
+pub struct BlockDeviceMetrics {
+    /// Number of times activating the device failed
+    pub activate_fails: u64,
+    /// Number of times configuring the device failed
+    pub cfg_fails: u64,
+    /// Number of failed request executions
+    pub execute_fails: u64,
+    /// Number of invalid requests
+    pub invalid_reqs_count: u64,
+    /// Number of flush operations
+    pub flush_count: u64,
+    /// Number of bytes read
+    pub read_bytes: u64,
+    /// Number of bytes written
+    pub write_bytes: u64,
+    /// Number of read operations
+    pub read_count: u64,
+    /// Number of write operations
+    pub write_count: u64,
+    /// Number of events throttled by the rate limiter
+    pub rate_limiter_throttled_events: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Default)]
+#[serde(default)]
+pub struct NetDeviceMetrics {
+    /// Number of times activating the device failed
+    pub activate_fails: u64,
+    /// Number of times configuring the device failed
+    pub cfg_fails: u64,
+    /// Number of bytes received
+    pub rx_bytes_count: u64,
+    /// Number of packets received
+    pub rx_packets_count: u64,
+    /// Number of failed receives
+    pub rx_fails: u64,
+    /// Number of receives throttled by the rate limiter
+    pub rx_rate_limiter_throttled: u64,
+    /// Number of bytes transmitted
+    pub tx_bytes_count: u64,
+    /// Number of packets transmitted
+    pub tx_packets_count: u64,
+    /// Number of failed transmits
+    pub tx_fails: u64,
+    /// Number of transmits throttled by the rate limiter
+    pub tx_rate_limiter_throttled: u64,
+    /// Number of failed reads from the TAP device
+    pub tap_read_fails: u64,
+    /// Number of failed writes to the TAP device
+    pub tap_write_fails: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Default)]
+#[serde(default)]
+pub struct VcpuMetrics {
+    /// Number of KVM exits for handling input IO
+    pub exit_io_in: u64,
+    /// Number of KVM exits for handling output IO
+    pub exit_io_out: u64,
+    /// Number of KVM exits for handling MMIO reads
+    pub exit_mmio_read: u64,
+    /// Number of KVM exits for handling MMIO writes
+    pub exit_mmio_write: u64,
+    /// Number of errors during this vCPU's run
+    pub failures: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Default)]
+#[serde(default)]
+pub struct VmmMetrics {
+    /// Number of device related events received for a VM
+    pub device_events: u64,
+    /// Number of times the VMM panicked
+    pub panic_count: u64,
+}
+
+/// Configures Firecracker to log to a FIFO in its jail at the given `level`, and returns a stream of the log lines. Pre-boot only.
+pub async fn logs(
+    client: &Client,
+    config: &Config<'_>,
+    level: LogLevel,
+) -> Result<LogStream, Error> {
+    let fifo = create_fifo(config, LOG_FIFO)?;
+    client
+        .set_logger(&Logger {
+            log_path: LOG_FIFO.into(),
+            level: Some(level),
+            show_level: Some(true),
+            show_log_origin: None,
+        })
+        .await?;
+
+    let file = open_fifo(&fifo)?;
+    Ok(spawn_reader(fifo, file, Ok))
+}
+
+/// Configures Firecracker to write metrics to a FIFO in its jail, and returns a stream of the parsed metrics. Pre-boot only.
+pub async fn metrics(client: &Client, config: &Config<'_>) -> Result<MetricsStream, Error> {
+    let fifo = create_fifo(config, METRICS_FIFO)?;
+    client
+        .set_metrics(&Metrics {
+            metrics_path: METRICS_FIFO.into(),
+        })
+        .await?;
+
+    let file = open_fifo(&fifo)?;
+    Ok(spawn_reader(fifo, file, |line| {
+        serde_json::from_str(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }))
+}
+
+/// Creates a FIFO at `jail_path` inside the jail, owned by the jail user. Returns the path to the FIFO on the host.
+fn create_fifo(config: &Config<'_>, jail_path: &str) -> Result<PathBuf, Error> {
+    let path = config.chroot_path().join(jail_path.trim_start_matches('/'));
+    mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(|error| Error::System {
+        context: format!("could not create FIFO {}", path.display()),
+        error,
+    })?;
+    chown(&path, Some(config.user()), Some(config.group())).map_err(|error| Error::System {
+        context: format!("could not change ownership of {}", path.display()),
+        error,
+    })?;
+    Ok(path)
+}
+
+/// Opens a FIFO for reading, once Firecracker has opened it for writing.
+///
+/// The FIFO is opened non-blocking so that this never waits for a writer, and then switched to blocking mode for the reader thread. Since
+/// Firecracker already holds the write end, reads will block until output is available and reach end-of-file once Firecracker exits.
+fn open_fifo(path: &Path) -> Result<File, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|error| Error::Io {
+            context: format!("could not open FIFO {}", path.display()),
+            error,
+        })?;
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty())).map_err(|error| Error::System {
+        context: format!("could not make FIFO {} blocking", path.display()),
+        error,
+    })?;
+    Ok(file)
+}
+
+/// Starts a background thread that reads lines from `file`, parses them with `parse`, and sends them to the returned stream. The thread
+/// exits when the FIFO is closed or the stream is dropped.
+fn spawn_reader<T, F>(path: PathBuf, file: File, parse: F) -> FifoStream<T>
+where
+    T: Send + 'static,
+    F: Fn(String) -> io::Result<T> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+    thread::spawn(move || {
+        for line in BufReader::new(file).lines() {
+            let item = line.and_then(&parse).map_err(|error| Error::Io {
+                context: format!("could not read from FIFO {}", path.display()),
+                error,
+            });
+            if sender.blocking_send(item).is_err() {
+                // The stream was dropped, so nobody is listening
+                return;
+            }
+        }
+        warn!("FIFO {} closed", path.display());
+    });
+
+    FifoStream { receiver }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metrics() {
+        let line = r#"{"utc_timestamp_ms":1612375013017,"api_server":{"process_startup_time_us":1437,"process_startup_time_cpu_us":0,"sync_response_fails":0,"sync_vmm_send_timeout_count":0},"block":{"activate_fails":0,"cfg_fails":0,"no_avail_buffer":0,"event_fails":0,"execute_fails":0,"invalid_reqs_count":0,"flush_count":0,"queue_event_count":12,"rate_limiter_event_count":0,"update_count":0,"update_fails":0,"read_bytes":167936,"write_bytes":0,"read_count":41,"write_count":0,"rate_limiter_throttled_events":0},"block_rootfs":{"read_bytes":167936},"vcpu":{"exit_io_in":28,"exit_io_out":1204,"exit_mmio_read":34,"exit_mmio_write":40,"failures":0}}"#;
+
+        let report: MetricsReport = serde_json::from_str(line).unwrap();
+        assert_eq!(report.utc_timestamp_ms, 1612375013017);
+        assert_eq!(report.api_server.process_startup_time_us, 1437);
+        assert_eq!(report.block.read_bytes, 167936);
+        assert_eq!(report.block.read_count, 41);
+        assert_eq!(report.vcpu.exit_io_out, 1204);
+        assert_eq!(report.net, NetDeviceMetrics::default());
+        assert_eq!(
+            report.other.get("block_rootfs"),
+            Some(&serde_json::json!({"read_bytes": 167936}))
+        );
+    }
+}
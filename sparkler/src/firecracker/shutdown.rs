@@ -0,0 +1,280 @@
+//! Bounded microVM shutdown
+//!
+//! [`shutdown`] first asks the guest to shut down by sending Ctrl+Alt+Del, which makes Firecracker exit if the guest kernel was booted with
+//! `reboot=k`. If the process is still running after the grace period, it is sent `SIGTERM` and then `SIGKILL`.
+//!
+//! The jailer runs Firecracker as the init process of a new PID namespace. Signals from outside the namespace only reach it if Firecracker
+//! handles them, so `SIGTERM` may have no effect, but `SIGKILL` is always delivered and takes down the entire namespace.
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use tokio::time;
+use tracing::{info, warn};
+use unshare::{Child, ExitStatus, Signal};
+
+use super::api::{ActionType, Client};
+use super::jailer;
+use crate::Error;
+
+/// How often to check whether the process has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Default time to wait for the guest to shut down after Ctrl+Alt+Del
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Default time to wait for Firecracker to exit after `SIGTERM`
+const DEFAULT_TERM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time to wait for Firecracker to exit after `SIGKILL`. This should be nearly instant.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long [`shutdown`] waits at each step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShutdownPolicy {
+    /// Time to wait for the guest to shut down after Ctrl+Alt+Del
+    pub grace_period: Duration,
+
+    /// Time to wait for Firecracker to exit after `SIGTERM`, before sending `SIGKILL`
+    pub term_timeout: Duration,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> ShutdownPolicy {
+        ShutdownPolicy {
+            grace_period: DEFAULT_GRACE_PERIOD,
+            term_timeout: DEFAULT_TERM_TIMEOUT,
+        }
+    }
+}
+
+/// How a microVM was shut down.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// Firecracker exited within the grace period after Ctrl+Alt+Del.
+    Clean(ExitStatus),
+
+    /// Firecracker had to be killed with `signal`.
+    Forced {
+        signal: Signal,
+        exit_status: ExitStatus,
+    },
+
+    /// Firecracker had already exited.
+    AlreadyDead(ExitStatus),
+}
+
+impl ShutdownOutcome {
+    /// Exit status of the jailer process
+    pub fn exit_status(&self) -> ExitStatus {
+        match *self {
+            ShutdownOutcome::Clean(exit_status) => exit_status,
+            ShutdownOutcome::Forced { exit_status, .. } => exit_status,
+            ShutdownOutcome::AlreadyDead(exit_status) => exit_status,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownOutcome::Clean(exit_status) => write!(f, "shut down cleanly ({})", exit_status),
+            ShutdownOutcome::Forced {
+                signal,
+                exit_status,
+            } => write!(f, "forced to stop with {:?} ({})", signal, exit_status),
+            ShutdownOutcome::AlreadyDead(exit_status) => {
+                write!(f, "already exited ({})", exit_status)
+            }
+        }
+    }
+}
+
+/// Shuts down the microVM served by `client` and run by `process`, escalating from Ctrl+Alt+Del to `SIGTERM` to `SIGKILL` according to
+/// `policy`. This takes at most `policy.grace_period + policy.term_timeout`, plus a few seconds for `SIGKILL`: sending Ctrl+Alt+Del
+/// counts against the grace period. The process is reaped before returning.
+pub async fn shutdown(
+    client: &Client,
+    process: &mut Child,
+    policy: &ShutdownPolicy,
+) -> Result<ShutdownOutcome, Error> {
+    if let Some(exit_status) = wait_for_exit(process, Duration::from_secs(0)).await? {
+        return Ok(ShutdownOutcome::AlreadyDead(exit_status));
+    }
+
+    info!("Sending Ctrl+Alt+Del to microVM");
+    let deadline = Instant::now() + policy.grace_period;
+    // Retrying would eat into the grace period, and the guest may act on a request that seemed to fail
+    match client
+        .with_timeout(policy.grace_period)
+        .without_retries()
+        .action(ActionType::SendCtrlAltDel)
+        .await
+    {
+        Ok(()) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Some(exit_status) = wait_for_exit(process, remaining).await? {
+                return Ok(ShutdownOutcome::Clean(exit_status));
+            }
+            warn!("microVM did not shut down within {:?}", policy.grace_period);
+        }
+        // For example, if the microVM was never started, or the API is unresponsive
+        Err(error) => warn!("Could not send Ctrl+Alt+Del: {}", error),
+    }
+
+    let mut sent: Option<Signal> = None;
+    for &(signal, timeout) in &[
+        (Signal::SIGTERM, policy.term_timeout),
+        (Signal::SIGKILL, KILL_TIMEOUT),
+    ] {
+        info!("Sending {:?} to jailer process {}", signal, process.pid());
+        if let Err(error) = process.signal(signal) {
+            // The process may have exited in the meantime, in response to whatever was sent last
+            if let Some(exit_status) = wait_for_exit(process, Duration::from_secs(0)).await? {
+                return Ok(match sent {
+                    Some(signal) => ShutdownOutcome::Forced {
+                        signal,
+                        exit_status,
+                    },
+                    None => ShutdownOutcome::Clean(exit_status),
+                });
+            }
+            return Err(Error::Io {
+                context: format!(
+                    "could not send {:?} to jailer process {}",
+                    signal,
+                    process.pid()
+                ),
+                error,
+            });
+        }
+
+        sent = Some(signal);
+
+        if let Some(exit_status) = wait_for_exit(process, timeout).await? {
+            return Ok(ShutdownOutcome::Forced {
+                signal,
+                exit_status,
+            });
+        }
+    }
+
+    // Most likely stuck in uninterruptible sleep, such as on a hung NFS mount
+    Err(Error::Io {
+        context: format!(
+            "jailer process {} did not exit after SIGKILL",
+            process.pid()
+        ),
+        error: io::ErrorKind::TimedOut.into(),
+    })
+}
+
+/// Waits up to `timeout` for `process` to exit, and reaps it if it did.
+async fn wait_for_exit(
+    process: &mut Child,
+    timeout: Duration,
+) -> Result<Option<ExitStatus>, Error> {
+    let start = Instant::now();
+    loop {
+        if jailer::peek_exit_status(process)?.is_some() {
+            // The process has exited, so this won't block
+            let exit_status = process.wait().map_err(|error| Error::Io {
+                context: format!("waiting for jailer process {} failed", process.pid()),
+                error,
+            })?;
+            return Ok(Some(exit_status));
+        }
+
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use unshare::Command;
+
+    use super::super::api::mock::MockServer;
+    use super::super::api::InstanceState;
+    use super::*;
+    use crate::util::TempDir;
+
+    #[tokio::test]
+    async fn test_clean() {
+        let server = MockServer::start_temp().unwrap();
+        server.state().instance_state = InstanceState::Running;
+        let client = server.client();
+        let temp = TempDir::new("shutdown");
+        let powered_off = temp.path().join("powered-off");
+
+        // Stands in for Firecracker, exiting once the guest has been asked to shut down
+        let mut process = Command::new("/bin/sh")
+            .arg("-c")
+            .arg("while [ ! -e \"$1\" ]; do sleep 0.05; done")
+            .arg("firecracker")
+            .arg(&powered_off)
+            .spawn()
+            .unwrap();
+        let guest = async {
+            while !server.state().actions.contains(&ActionType::SendCtrlAltDel) {
+                time::sleep(POLL_INTERVAL).await;
+            }
+            fs::write(&powered_off, "").unwrap();
+        };
+
+        let policy = ShutdownPolicy::default();
+        let (outcome, ()) = tokio::join!(shutdown(&client, &mut process, &policy), guest);
+        assert_eq!(
+            outcome.unwrap(),
+            ShutdownOutcome::Clean(ExitStatus::Exited(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_escalation() {
+        // The mock VM was never started, so Ctrl+Alt+Del fails and shutdown goes straight to signals
+        let server = MockServer::start_temp().unwrap();
+        let client = server.client();
+        let policy = ShutdownPolicy {
+            grace_period: Duration::from_millis(100),
+            term_timeout: Duration::from_millis(100),
+        };
+
+        let mut process = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        assert_eq!(
+            shutdown(&client, &mut process, &policy).await.unwrap(),
+            ShutdownOutcome::Forced {
+                signal: Signal::SIGTERM,
+                exit_status: ExitStatus::Signaled(Signal::SIGTERM, false),
+            }
+        );
+
+        let mut process = Command::new("/bin/sh")
+            .arg("-c")
+            .arg("trap '' TERM; sleep 10")
+            .spawn()
+            .unwrap();
+        // Give the shell time to ignore SIGTERM
+        time::sleep(Duration::from_millis(100)).await;
+        let outcome = shutdown(&client, &mut process, &policy).await.unwrap();
+        assert!(matches!(
+            outcome,
+            ShutdownOutcome::Forced {
+                signal: Signal::SIGKILL,
+                ..
+            }
+        ));
+
+        let mut process = Command::new("/bin/true").spawn().unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            shutdown(&client, &mut process, &policy).await.unwrap(),
+            ShutdownOutcome::AlreadyDead(ExitStatus::Exited(0))
+        );
+    }
+}
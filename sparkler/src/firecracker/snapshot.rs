@@ -0,0 +1,221 @@
+//! Saving and restoring microVM snapshots
+//!
+//! Firecracker can only read and write files inside its jail, so snapshots are written to a staging directory inside the chroot and then moved
+//! next to it, into the VM's state directory (the parent of [`Config::chroot_path`]). Restoring copies the files back into the new VM's jail
+//! before asking Firecracker to load them. The copies are reflinks where the filesystem supports them, so this is cheap even for large
+//! memory files, and Firecracker never gets write access to the saved snapshot itself.
+//!
+//! Since the snapshot lives in the VM's state directory, it is removed along with the rest of the VM state unless it is moved elsewhere.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{chown, Gid, Uid};
+use tokio::task::spawn_blocking;
+
+use super::api::{
+    Client, MemoryBackend, MemoryBackendType, SnapshotCreateParams, SnapshotLoadParams,
+    SnapshotType,
+};
+use super::jailer::Config;
+use crate::util::reflink_or_copy;
+use crate::Error;
+
+/// Name of the staging directory inside the jail, and of the snapshot directory next to the jail.
+const SNAPSHOT_DIRECTORY: &str = "snapshot";
+
+/// File name for the microVM state
+const STATE_FILE: &str = "vmstate";
+
+/// File name for the guest memory
+const MEMORY_FILE: &str = "memory";
+
+/// A microVM snapshot saved on the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    directory: PathBuf,
+}
+
+impl Snapshot {
+    /// Refers to a snapshot previously saved into `directory`.
+    pub fn open<P: Into<PathBuf>>(directory: P) -> Snapshot {
+        Snapshot {
+            directory: directory.into(),
+        }
+    }
+
+    /// Directory containing the snapshot files.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Path to the microVM state file.
+    pub fn state_path(&self) -> PathBuf {
+        self.directory.join(STATE_FILE)
+    }
+
+    /// Path to the guest memory file.
+    pub fn memory_path(&self) -> PathBuf {
+        self.directory.join(MEMORY_FILE)
+    }
+}
+
+/// Directory that snapshots of the VM described by `config` are saved to.
+///
+/// This takes the form `$chroot_base/$(basename $firecracker_binary)/$id/snapshot`, alongside the `root` chroot directory.
+pub fn snapshot_directory(config: &Config<'_>) -> PathBuf {
+    config
+        .chroot_path()
+        .parent()
+        .expect("chroot path has no parent")
+        .join(SNAPSHOT_DIRECTORY)
+}
+
+/// Snapshots a running VM into its [`snapshot_directory`]. The VM is paused while the snapshot is taken, and resumed afterwards.
+///
+/// A [`SnapshotType::Diff`] snapshot only contains the guest memory dirtied since the last snapshot, so it is written on top of the memory file
+/// of the previous snapshot in the same directory.
+pub async fn save(
+    client: &Client,
+    config: &Config<'_>,
+    snapshot_type: SnapshotType,
+) -> Result<Snapshot, Error> {
+    let snapshot = Snapshot::open(snapshot_directory(config));
+    let staging = config.chroot_path().join(SNAPSHOT_DIRECTORY);
+
+    {
+        let snapshot = snapshot.clone();
+        let staging = staging.clone();
+        let (user, group) = (config.user(), config.group());
+        spawn_blocking(move || {
+            create_jail_directory(&staging, user, group)?;
+            if snapshot_type == SnapshotType::Diff {
+                stage_file(
+                    &snapshot.memory_path(),
+                    &staging.join(MEMORY_FILE),
+                    user,
+                    group,
+                )?;
+            }
+            Ok::<_, Error>(())
+        })
+        .await
+        .unwrap()?;
+    }
+
+    client.pause().await?;
+    let result = client
+        .create_snapshot(&SnapshotCreateParams {
+            mem_file_path: jail_path(MEMORY_FILE),
+            snapshot_path: jail_path(STATE_FILE),
+            snapshot_type: Some(snapshot_type),
+            version: None,
+        })
+        .await;
+    // Resume even if the snapshot failed, so the VM isn't left paused
+    client.resume().await?;
+    result?;
+
+    let saved = snapshot.clone();
+    spawn_blocking(move || {
+        fs::create_dir_all(&saved.directory).map_err(|error| Error::Io {
+            context: format!(
+                "could not create snapshot directory {}",
+                saved.directory.display()
+            ),
+            error,
+        })?;
+        for (from, to) in [
+            (staging.join(STATE_FILE), saved.state_path()),
+            (staging.join(MEMORY_FILE), saved.memory_path()),
+        ]
+        .iter()
+        {
+            fs::rename(from, to).map_err(|error| Error::Io {
+                context: format!("could not move {} to {}", from.display(), to.display()),
+                error,
+            })?;
+        }
+        fs::remove_dir(&staging).map_err(|error| Error::Io {
+            context: format!("could not remove {}", staging.display()),
+            error,
+        })
+    })
+    .await
+    .unwrap()?;
+
+    Ok(snapshot)
+}
+
+/// Restores `snapshot` into the fresh Firecracker process behind `client`, which must not have been configured yet. If `resume` is true,
+/// the VM starts running as soon as the snapshot is loaded; otherwise it is left paused.
+pub async fn restore(
+    client: &Client,
+    config: &Config<'_>,
+    snapshot: &Snapshot,
+    resume: bool,
+) -> Result<(), Error> {
+    let staging = config.chroot_path().join(SNAPSHOT_DIRECTORY);
+
+    {
+        let snapshot = snapshot.clone();
+        let (user, group) = (config.user(), config.group());
+        spawn_blocking(move || {
+            create_jail_directory(&staging, user, group)?;
+            stage_file(
+                &snapshot.state_path(),
+                &staging.join(STATE_FILE),
+                user,
+                group,
+            )?;
+            stage_file(
+                &snapshot.memory_path(),
+                &staging.join(MEMORY_FILE),
+                user,
+                group,
+            )
+        })
+        .await
+        .unwrap()?;
+    }
+
+    client
+        .load_snapshot(&SnapshotLoadParams {
+            enable_diff_snapshots: false,
+            mem_backend: MemoryBackend {
+                backend_type: MemoryBackendType::File,
+                backend_path: jail_path(MEMORY_FILE),
+            },
+            snapshot_path: jail_path(STATE_FILE),
+            resume_vm: resume,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Path to a snapshot file, as seen by Firecracker inside the jail.
+fn jail_path(file: &str) -> PathBuf {
+    Path::new("/").join(SNAPSHOT_DIRECTORY).join(file)
+}
+
+/// Creates a directory inside the jail that Firecracker can write to.
+fn create_jail_directory(path: &Path, user: Uid, group: Gid) -> Result<(), Error> {
+    fs::create_dir_all(path).map_err(|error| Error::Io {
+        context: format!("could not create {}", path.display()),
+        error,
+    })?;
+    chown(path, Some(user), Some(group)).map_err(|error| Error::System {
+        context: format!("could not change ownership of {}", path.display()),
+        error,
+    })
+}
+
+/// Copies a snapshot file into the jail, owned by the jail user. The copy is chowned, so it must not share an inode with `source`.
+fn stage_file(source: &Path, target: &Path, user: Uid, group: Gid) -> Result<(), Error> {
+    reflink_or_copy(source, target)?;
+    chown(target, Some(user), Some(group)).map_err(|error| Error::System {
+        context: format!("could not change ownership of {}", target.display()),
+        error,
+    })
+}
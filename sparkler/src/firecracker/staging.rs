@@ -0,0 +1,257 @@
+//! Staging boot resources into a jail
+//!
+//! Firecracker can only open files inside its chroot, so kernels, initrds and drive images have to be placed in the jail before they are
+//! configured. [`Stager`] puts each file at a fixed location in the jail, named after what it is used for, and returns the path Firecracker
+//! should be given.
+//!
+//! Files Firecracker can write to, like writable drives, get a private copy owned by the jail user, which is a reflink where the filesystem
+//! supports it (see [`reflink_or_copy`]). Files it only reads, like kernels and read-only drives, are hard-linked when the image and the jail
+//! are on the same filesystem (see [`link_or_copy`]). A hard link shares its inode with the image on the host, so it is never chowned; if
+//! the jail user can't read the image as it is, it is copied instead.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{chown, Gid, Uid};
+
+use super::api::{resource_path, BootSource, Drive};
+use super::jailer::Config;
+use crate::util::{link_or_copy, reflink_or_copy};
+use crate::Error;
+
+/// Path of the kernel inside the jail
+pub const KERNEL_FILE: &str = "/vmlinux";
+
+/// Path of the initrd inside the jail
+pub const INITRD_FILE: &str = "/initrd";
+
+/// Directory inside the jail for drive images, which are named after their drive IDs
+pub const DRIVE_DIRECTORY: &str = "/drives";
+
+/// Stages host files into a microVM's jail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stager {
+    chroot_path: PathBuf,
+    user: Uid,
+    group: Gid,
+}
+
+impl Stager {
+    /// Creates a stager for the jail described by `config`.
+    pub fn new(config: &Config<'_>) -> Stager {
+        Stager {
+            chroot_path: config.chroot_path(),
+            user: config.user(),
+            group: config.group(),
+        }
+    }
+
+    /// Path on the host corresponding to `jail_path` inside the jail.
+    pub fn host_path<P: AsRef<Path>>(&self, jail_path: P) -> PathBuf {
+        self.chroot_path.join(
+            jail_path
+                .as_ref()
+                .strip_prefix("/")
+                .unwrap_or(jail_path.as_ref()),
+        )
+    }
+
+    /// Copies `source` to `jail_path` inside the jail, owned by the jail user, replacing any existing file. Firecracker can write to the copy
+    /// without affecting `source`. Returns `jail_path`.
+    pub fn stage<P: AsRef<Path>>(&self, source: &Path, jail_path: P) -> Result<PathBuf, Error> {
+        let jail_path = jail_path.as_ref();
+        let target = self.prepare(jail_path)?;
+        reflink_or_copy(source, &target)?;
+        self.chown(&target)?;
+        Ok(Path::new("/").join(jail_path))
+    }
+
+    /// Links `source` to `jail_path` inside the jail for Firecracker to read, replacing any existing file. If the files are on different
+    /// filesystems, or the jail user can't read `source`, it is copied as with [`Stager::stage`] instead. Returns `jail_path`.
+    pub fn stage_read_only<P: AsRef<Path>>(
+        &self,
+        source: &Path,
+        jail_path: P,
+    ) -> Result<PathBuf, Error> {
+        let jail_path = jail_path.as_ref();
+        let target = self.prepare(jail_path)?;
+        if link_or_copy(source, &target)? {
+            let metadata = fs::metadata(&target).map_err(|error| Error::Io {
+                context: format!("could not read metadata for {}", target.display()),
+                error,
+            })?;
+            if self.can_read(&metadata) {
+                return Ok(Path::new("/").join(jail_path));
+            }
+            remove_existing(&target)?;
+            reflink_or_copy(source, &target)?;
+        }
+        self.chown(&target)?;
+        Ok(Path::new("/").join(jail_path))
+    }
+
+    /// Creates the parent directory of `jail_path` and removes any existing file there, returning its path on the host.
+    fn prepare(&self, jail_path: &Path) -> Result<PathBuf, Error> {
+        let target = self.host_path(jail_path);
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|error| Error::Io {
+                context: format!("could not create jail directory {}", parent.display()),
+                error,
+            })?;
+        }
+        remove_existing(&target)?;
+        Ok(target)
+    }
+
+    fn chown(&self, target: &Path) -> Result<(), Error> {
+        chown(target, Some(self.user), Some(self.group)).map_err(|error| Error::System {
+            context: format!("could not change ownership of {}", target.display()),
+            error,
+        })
+    }
+
+    /// Whether the jail user can read a file with `metadata`, going by its owner and permission bits.
+    fn can_read(&self, metadata: &fs::Metadata) -> bool {
+        let mode = metadata.mode();
+        if self.user.is_root() || mode & 0o004 != 0 {
+            true
+        } else if metadata.uid() == self.user.as_raw() {
+            mode & 0o400 != 0
+        } else {
+            metadata.gid() == self.group.as_raw() && mode & 0o040 != 0
+        }
+    }
+
+    /// Stages `kernel`, and `initrd` if there is one, returning a boot source that refers to them.
+    pub fn stage_boot_source(
+        &self,
+        kernel: &Path,
+        initrd: Option<&Path>,
+        boot_args: Option<String>,
+    ) -> Result<BootSource, Error> {
+        Ok(BootSource {
+            kernel_image_path: self.stage_read_only(kernel, KERNEL_FILE)?,
+            initrd_path: initrd
+                .map(|initrd| self.stage_read_only(initrd, INITRD_FILE))
+                .transpose()?,
+            boot_args,
+        })
+    }
+
+    /// Stages the drive image `source` into [`DRIVE_DIRECTORY`], returning a drive that refers to it. Writable drives are copied, so the
+    /// guest can't modify `source`.
+    pub fn stage_drive(
+        &self,
+        drive_id: &str,
+        source: &Path,
+        is_root_device: bool,
+        is_read_only: bool,
+    ) -> Result<Drive, Error> {
+        // The API restricts drive IDs to characters that are safe in file names
+        let jail_path = resource_path(DRIVE_DIRECTORY, drive_id)?;
+        let path_on_host = if is_read_only {
+            self.stage_read_only(source, jail_path)?
+        } else {
+            self.stage(source, jail_path)?
+        };
+
+        Ok(Drive {
+            drive_id: drive_id.to_string(),
+            is_read_only,
+            is_root_device,
+            partuuid: None,
+            path_on_host,
+            rate_limiter: None,
+        })
+    }
+}
+
+/// Removes the file at `path`, if there is one.
+fn remove_existing(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(Error::Io {
+            context: format!("could not replace {}", path.display()),
+            error,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::super::jailer::ConfigBuilder;
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_stage() {
+        let temp = TempDir::new("staging");
+        let base = temp.path();
+        let images = base.join("images");
+        fs::create_dir_all(&images).unwrap();
+        fs::write(images.join("vmlinux.bin"), "kernel").unwrap();
+        fs::write(images.join("rootfs.ext4"), "rootfs").unwrap();
+
+        let chroot_base = base.join("jails");
+        let config = ConfigBuilder::default()
+            .id("staging")
+            .user(Uid::current())
+            .group(Gid::current())
+            .chroot_base(chroot_base.as_path())
+            .build()
+            .unwrap();
+        let stager = Stager::new(&config);
+
+        let boot_source = stager
+            .stage_boot_source(&images.join("vmlinux.bin"), None, None)
+            .unwrap();
+        assert_eq!(boot_source.kernel_image_path, Path::new(KERNEL_FILE));
+        assert_eq!(boot_source.initrd_path, None);
+
+        let drive = stager
+            .stage_drive("rootfs", &images.join("rootfs.ext4"), true, false)
+            .unwrap();
+        assert_eq!(drive.path_on_host, Path::new("/drives/rootfs"));
+        let staged = stager.host_path(&drive.path_on_host);
+        assert_eq!(staged, config.chroot_path().join("drives/rootfs"));
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "rootfs");
+        // Writable drives are never linked
+        let ino = |path: &Path| fs::metadata(path).unwrap().ino();
+        assert_ne!(ino(&staged), ino(&images.join("rootfs.ext4")));
+
+        // Read-only files on the same filesystem are linked, if the jail user can read them
+        let kernel = stager.host_path(&boot_source.kernel_image_path);
+        assert_eq!(ino(&kernel), ino(&images.join("vmlinux.bin")));
+        let drive = stager
+            .stage_drive("data", &images.join("rootfs.ext4"), false, true)
+            .unwrap();
+        let staged = stager.host_path(&drive.path_on_host);
+        assert_eq!(ino(&staged), ino(&images.join("rootfs.ext4")));
+
+        fs::set_permissions(
+            images.join("vmlinux.bin"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        let other = Stager {
+            user: Uid::from_raw(Uid::current().as_raw() + 1),
+            ..stager.clone()
+        };
+        assert!(!other.can_read(&fs::metadata(images.join("vmlinux.bin")).unwrap()));
+        assert!(stager.can_read(&fs::metadata(images.join("vmlinux.bin")).unwrap()));
+
+        // Staging again replaces the file
+        stager
+            .stage_drive("rootfs", &images.join("rootfs.ext4"), true, false)
+            .unwrap();
+        assert!(matches!(
+            stager.stage_drive("../rootfs", &images.join("rootfs.ext4"), true, false),
+            Err(Error::Api(super::super::api::Error::InvalidId(_)))
+        ));
+    }
+}
@@ -0,0 +1,631 @@
+//! Typed microVM lifecycle
+//!
+//! [`MicroVm`] owns a Firecracker process and its API client, and uses its type parameter to track which lifecycle state the microVM is in.
+//! Each state only exposes the operations Firecracker accepts in that state, so mistakes like changing the boot source after boot are caught
+//! at compile time instead of by a 400 from the VMM:
+//!
+//! ```text
+//! Configuring --start--> Running <--pause/resume--> Paused
+//!      |                    |                          |
+//!      +--load_snapshot-----|------------------------> +
+//!                           +--------stop/wait-------> Stopped <--stop--+
+//! ```
+//!
+//! Transitions consume the microVM. If a transition fails, the [`TransitionError`] hands the microVM back in its previous state.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::task::spawn_blocking;
+use tokio::time;
+use tracing::info;
+
+use super::api::{
+    self, ActionType, BalloonUpdate, Client, InstanceInfo, InstanceState, PartialDrive,
+    PartialNetworkInterface, SnapshotCreateParams, SnapshotLoadParams,
+};
+use super::config::VmConfig;
+use super::console::{self, Console};
+use super::jailer::{self, users::DynamicUser};
+use super::shutdown::{self, ShutdownOutcome, ShutdownPolicy};
+use crate::Error;
+
+/// How often to check whether the jailer exited while waiting for Firecracker to start
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for the Firecracker API when starting a microVM
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest time to wait for each request when checking whether the API of a Firecracker process that isn't owned has gone away
+const API_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The microVM has not been started yet, and can be configured.
+#[derive(Debug)]
+pub struct Configuring;
+
+/// The microVM is running.
+#[derive(Debug)]
+pub struct Running;
+
+/// The microVM is paused, and can be snapshotted.
+#[derive(Debug)]
+pub struct Paused;
+
+/// The Firecracker process has exited.
+#[derive(Debug)]
+pub struct Stopped {
+    exit_status: Option<unshare::ExitStatus>,
+    shutdown: Option<ShutdownOutcome>,
+}
+
+/// A Firecracker microVM in lifecycle state `S`.
+pub struct MicroVm<S> {
+    client: Client,
+    process: Option<unshare::Child>,
+    console: Option<Arc<Console>>,
+    /// Dynamic user that Firecracker runs as, released when this `MicroVm` is dropped
+    user: Option<DynamicUser>,
+    state: S,
+}
+
+impl<S: fmt::Debug> fmt::Debug for MicroVm<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MicroVm")
+            .field("pid", &self.pid())
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+/// A failed lifecycle transition. The microVM is returned in the state it was in before the transition.
+pub struct TransitionError<S> {
+    pub vm: MicroVm<S>,
+    pub error: Error,
+}
+
+impl<S> fmt::Debug for TransitionError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransitionError")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<S> From<TransitionError<S>> for Error {
+    fn from(error: TransitionError<S>) -> Error {
+        error.error
+    }
+}
+
+/// Result of a lifecycle transition from state `From` to state `To`.
+pub type Transition<From, To> = Result<MicroVm<To>, TransitionError<From>>;
+
+/// A microVM managed through [`MicroVm::attach`], in the state Firecracker reported when it was attached to.
+#[derive(Debug)]
+pub enum AttachedVm {
+    Configuring(MicroVm<Configuring>),
+    Running(MicroVm<Running>),
+    Paused(MicroVm<Paused>),
+}
+
+impl<S> MicroVm<S> {
+    /// Returns general information about the microVM.
+    pub async fn info(&self) -> Result<InstanceInfo, Error> {
+        Ok(self.client.instance_info().await?)
+    }
+
+    /// Returns the state Firecracker reports for the microVM.
+    pub async fn instance_state(&self) -> Result<InstanceState, Error> {
+        Ok(self.info().await?.state)
+    }
+
+    /// Replaces the contents of the MMDS data store.
+    pub async fn put_mmds<T: serde::Serialize>(&self, data: &T) -> Result<(), Error> {
+        Ok(self.client.put_mmds(data).await?)
+    }
+
+    /// Merges `data` into the MMDS data store.
+    pub async fn patch_mmds<T: serde::Serialize>(&self, data: &T) -> Result<(), Error> {
+        Ok(self.client.patch_mmds(data).await?)
+    }
+
+    /// Process ID of the jailer, if this `MicroVm` owns the Firecracker process.
+    pub fn pid(&self) -> Option<nix::libc::pid_t> {
+        self.process.as_ref().map(|process| process.pid())
+    }
+
+    /// The guest serial console, if this `MicroVm` started the Firecracker process.
+    pub fn console(&self) -> Option<&Arc<Console>> {
+        self.console.as_ref()
+    }
+
+    fn into_state<T>(self, state: T) -> MicroVm<T> {
+        MicroVm {
+            client: self.client,
+            process: self.process,
+            console: self.console,
+            user: self.user,
+            state,
+        }
+    }
+
+    fn fail(self, error: Error) -> TransitionError<S> {
+        TransitionError { vm: self, error }
+    }
+
+    /// Checks that Firecracker reports the microVM in the `expected` state after a transition.
+    async fn verify_state(&self, expected: InstanceState) -> Result<(), Error> {
+        let actual = self.instance_state().await?;
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedState { expected, actual })
+        }
+    }
+
+    /// Kills the Firecracker process and waits for it to exit. A process this `MicroVm` doesn't own can't be killed, so this fails and hands
+    /// back the microVM.
+    async fn kill(self) -> Result<MicroVm<Stopped>, TransitionError<S>> {
+        let process = match &self.process {
+            Some(process) => process,
+            None => {
+                return Err(self.fail(Error::Io {
+                    context: "cannot kill a Firecracker process that was started elsewhere".into(),
+                    error: io::ErrorKind::Unsupported.into(),
+                }))
+            }
+        };
+        if let Err(error) = process.kill() {
+            let context = format!("could not kill jailer process {}", process.pid());
+            return Err(self.fail(Error::Io { context, error }));
+        }
+        self.wait_for_exit().await
+    }
+
+    /// Waits for the Firecracker process to exit. If the process is not owned, this waits for its API to go away instead.
+    async fn wait_for_exit(mut self) -> Result<MicroVm<Stopped>, TransitionError<S>> {
+        let process = match self.process.take() {
+            Some(process) => process,
+            None => {
+                self.wait_for_api_closed(None).await;
+                return Ok(self.into_state(Stopped {
+                    exit_status: None,
+                    shutdown: None,
+                }));
+            }
+        };
+
+        let (process, result) = spawn_blocking(move || {
+            let mut process = process;
+            let result = process.wait();
+            (process, result)
+        })
+        .await
+        .unwrap();
+
+        match result {
+            Ok(exit_status) => {
+                info!("Jailer complete: {}", exit_status);
+                self.process = Some(process);
+                Ok(self.into_state(Stopped {
+                    exit_status: Some(exit_status),
+                    shutdown: None,
+                }))
+            }
+            Err(error) => {
+                let context = format!("waiting for jailer process {} failed", process.pid());
+                self.process = Some(process);
+                Err(self.fail(Error::Io { context, error }))
+            }
+        }
+    }
+
+    /// Waits up to `timeout`, or indefinitely if `None`, for the Firecracker API to stop accepting connections, which is how the exit of a
+    /// process this `MicroVm` doesn't own is noticed. Returns whether it did.
+    async fn wait_for_api_closed(&self, timeout: Option<Duration>) -> bool {
+        let start = Instant::now();
+        loop {
+            let probe_timeout = timeout.map_or(API_PROBE_TIMEOUT, |timeout| {
+                timeout
+                    .saturating_sub(start.elapsed())
+                    .min(API_PROBE_TIMEOUT)
+            });
+            // A request that times out means the API is unresponsive, not gone
+            let probe = self.client.with_timeout(probe_timeout).without_retries();
+            if let Err(api::Error::Transport(error)) = probe.instance_info().await {
+                if error.is_connect() {
+                    return true;
+                }
+            }
+
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return false;
+            }
+            time::sleep(PROCESS_POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl MicroVm<Configuring> {
+    /// Starts Firecracker in the jail described by `config`, and waits for its API to become available. The guest serial console is captured
+    /// (see [`MicroVm::console`]).
+    pub async fn spawn(config: &jailer::Config<'_>) -> Result<MicroVm<Configuring>, Error> {
+        let socket_path = config.api_socket_path();
+        let (mut process, console) = console::spawn(config)?;
+
+        let client = Client::new(socket_path);
+
+        // Wait for the API, but give up early if the jailer exits
+        {
+            let ready = client.wait_ready(STARTUP_TIMEOUT);
+            tokio::pin!(ready);
+            let mut check_process = time::interval(PROCESS_POLL_INTERVAL);
+            loop {
+                tokio::select! {
+                    result = &mut ready => match result {
+                        Ok(_) => break,
+                        Err(_) => {
+                            // Reap the jailer so it isn't left as a zombie. It has been killed, so this doesn't block for long.
+                            let _ = process.kill();
+                            let _ = process.wait();
+                            return Err(Error::StartupTimeout {
+                                timeout: STARTUP_TIMEOUT,
+                                exit_status: None,
+                            });
+                        }
+                    },
+                    _ = check_process.tick() => {
+                        match jailer::peek_exit_status(&process) {
+                            Ok(None) => {}
+                            Ok(Some(exit_status)) => {
+                                let _ = process.wait();
+                                return Err(Error::StartupTimeout {
+                                    timeout: STARTUP_TIMEOUT,
+                                    exit_status: Some(exit_status),
+                                });
+                            }
+                            Err(error) => {
+                                let _ = process.kill();
+                                let _ = process.wait();
+                                return Err(error);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(MicroVm {
+            client,
+            process: Some(process),
+            console: Some(Arc::new(console)),
+            user: None,
+            state: Configuring,
+        })
+    }
+
+    /// Like [`MicroVm::spawn`], but runs Firecracker as a dynamically-allocated `user`, which `config` must be set to (see
+    /// [`ConfigBuilder::dynamic_user`](jailer::ConfigBuilder::dynamic_user)). The user is released when the `MicroVm` is dropped, so it should
+    /// be kept until the microVM has stopped.
+    pub async fn spawn_as(
+        config: &jailer::Config<'_>,
+        user: DynamicUser,
+    ) -> Result<MicroVm<Configuring>, Error> {
+        if config.user() != user.uid() || config.group() != user.gid() {
+            return Err(Error::InvalidJailerConfig(format!(
+                "jailer is configured to run as {}:{}, not dynamic user {}",
+                config.user(),
+                config.group(),
+                user.id()
+            )));
+        }
+
+        let mut vm = MicroVm::spawn(config).await?;
+        vm.user = Some(user);
+        Ok(vm)
+    }
+
+    /// Manages a Firecracker process that was started elsewhere, through `client`. The process is not owned, so stopping the microVM will not
+    /// kill it. Firecracker is asked which state the microVM is in, and it is returned in that state.
+    pub async fn attach(client: Client) -> Result<AttachedVm, Error> {
+        let vm = MicroVm {
+            client,
+            process: None,
+            console: None,
+            user: None,
+            state: Configuring,
+        };
+        Ok(match vm.instance_state().await? {
+            InstanceState::NotStarted => AttachedVm::Configuring(vm),
+            InstanceState::Running => AttachedVm::Running(vm.into_state(Running)),
+            InstanceState::Paused => AttachedVm::Paused(vm.into_state(Paused)),
+        })
+    }
+
+    /// Applies `config` through the API.
+    pub async fn configure(&self, config: &VmConfig) -> Result<(), Error> {
+        Ok(config.apply(&self.client).await?)
+    }
+
+    /// Boots the microVM.
+    pub async fn start(self) -> Transition<Configuring, Running> {
+        if let Err(error) = self.client.action(ActionType::InstanceStart).await {
+            return Err(self.fail(error.into()));
+        }
+        let vm = self.into_state(Running);
+        match vm.verify_state(InstanceState::Running).await {
+            Ok(()) => Ok(vm),
+            Err(error) => Err(vm.into_state(Configuring).fail(error)),
+        }
+    }
+
+    /// Loads a snapshot into the microVM, leaving it paused. The `resume_vm` setting in `params` is ignored; use [`MicroVm::resume`] to
+    /// continue running the restored microVM.
+    pub async fn load_snapshot(
+        self,
+        params: &SnapshotLoadParams,
+    ) -> Transition<Configuring, Paused> {
+        let params = SnapshotLoadParams {
+            resume_vm: false,
+            ..params.clone()
+        };
+        match self.client.load_snapshot(&params).await {
+            Ok(()) => Ok(self.into_state(Paused)),
+            Err(error) => Err(self.fail(error.into())),
+        }
+    }
+
+    /// Kills the Firecracker process without starting the microVM. This fails if this `MicroVm` doesn't own the process.
+    pub async fn stop(self) -> Transition<Configuring, Stopped> {
+        self.kill().await
+    }
+}
+
+impl MicroVm<Running> {
+    /// Pauses the microVM.
+    pub async fn pause(self) -> Transition<Running, Paused> {
+        match self.client.pause().await {
+            Ok(()) => Ok(self.into_state(Paused)),
+            Err(error) => Err(self.fail(error.into())),
+        }
+    }
+
+    /// Updates a drive's backing file or rate limiter.
+    pub async fn patch_drive(&self, update: &PartialDrive) -> Result<(), Error> {
+        Ok(self.client.patch_drive(update).await?)
+    }
+
+    /// Updates a network interface's rate limiters.
+    pub async fn patch_network_interface(
+        &self,
+        update: &PartialNetworkInterface,
+    ) -> Result<(), Error> {
+        Ok(self.client.patch_network_interface(update).await?)
+    }
+
+    /// Changes the target size of the balloon device.
+    pub async fn patch_balloon(&self, update: &BalloonUpdate) -> Result<(), Error> {
+        Ok(self.client.patch_balloon(update).await?)
+    }
+
+    /// Flushes metrics to the configured metrics output.
+    pub async fn flush_metrics(&self) -> Result<(), Error> {
+        Ok(self.client.action(ActionType::FlushMetrics).await?)
+    }
+
+    /// Sends Ctrl+Alt+Del to the guest, asking it to shut down. With `reboot=k` on the kernel command line, Firecracker exits once the guest
+    /// has shut down; use [`MicroVm::wait`] to wait for that.
+    pub async fn send_ctrl_alt_del(&self) -> Result<(), Error> {
+        Ok(self.client.action(ActionType::SendCtrlAltDel).await?)
+    }
+
+    /// Waits for the Firecracker process to exit on its own. If this `MicroVm` doesn't own the process, the exit is noticed by the API
+    /// refusing connections, and no exit status is available.
+    pub async fn wait(self) -> Transition<Running, Stopped> {
+        self.wait_for_exit().await
+    }
+
+    /// Kills the Firecracker process. This fails if this `MicroVm` doesn't own the process; use [`MicroVm::shutdown`] instead.
+    pub async fn stop(self) -> Transition<Running, Stopped> {
+        self.kill().await
+    }
+
+    /// Shuts the microVM down, starting with Ctrl+Alt+Del and escalating to signals as described by `policy`. See [`shutdown`] for details.
+    ///
+    /// If this `MicroVm` does not own the Firecracker process, it can't be signalled. Ctrl+Alt+Del is sent, and the microVM is only
+    /// considered stopped once the API stops accepting connections. If that doesn't happen within the grace period, this fails and hands
+    /// back the running microVM.
+    pub async fn shutdown(mut self, policy: &ShutdownPolicy) -> Transition<Running, Stopped> {
+        let mut process = match self.process.take() {
+            Some(process) => process,
+            None => return self.shutdown_unowned(policy).await,
+        };
+
+        let result = shutdown::shutdown(&self.client, &mut process, policy).await;
+        self.process = Some(process);
+        match result {
+            Ok(outcome) => {
+                info!("microVM {}", outcome);
+                Ok(self.into_state(Stopped {
+                    exit_status: Some(outcome.exit_status()),
+                    shutdown: Some(outcome),
+                }))
+            }
+            Err(error) => Err(self.fail(error)),
+        }
+    }
+
+    async fn shutdown_unowned(self, policy: &ShutdownPolicy) -> Transition<Running, Stopped> {
+        let start = Instant::now();
+        info!("Sending Ctrl+Alt+Del to microVM");
+        if let Err(error) = self
+            .client
+            .with_timeout(policy.grace_period)
+            .without_retries()
+            .action(ActionType::SendCtrlAltDel)
+            .await
+        {
+            return Err(self.fail(error.into()));
+        }
+
+        let remaining = policy.grace_period.saturating_sub(start.elapsed());
+        if self.wait_for_api_closed(Some(remaining)).await {
+            info!("microVM shut down");
+            Ok(self.into_state(Stopped {
+                exit_status: None,
+                shutdown: None,
+            }))
+        } else {
+            Err(self.fail(Error::Io {
+                context: format!("microVM did not shut down within {:?}", policy.grace_period),
+                error: io::ErrorKind::TimedOut.into(),
+            }))
+        }
+    }
+}
+
+impl MicroVm<Paused> {
+    /// Resumes the microVM.
+    pub async fn resume(self) -> Transition<Paused, Running> {
+        match self.client.resume().await {
+            Ok(()) => Ok(self.into_state(Running)),
+            Err(error) => Err(self.fail(error.into())),
+        }
+    }
+
+    /// Snapshots the microVM.
+    pub async fn create_snapshot(&self, params: &SnapshotCreateParams) -> Result<(), Error> {
+        Ok(self.client.create_snapshot(params).await?)
+    }
+
+    /// Kills the Firecracker process. This fails if this `MicroVm` doesn't own the process.
+    pub async fn stop(self) -> Transition<Paused, Stopped> {
+        self.kill().await
+    }
+}
+
+impl MicroVm<Stopped> {
+    /// Exit status of the jailer, if this `MicroVm` owned the Firecracker process.
+    pub fn exit_status(&self) -> Option<unshare::ExitStatus> {
+        self.state.exit_status
+    }
+
+    /// How the microVM was stopped, if it was stopped with [`MicroVm::shutdown`].
+    pub fn shutdown_outcome(&self) -> Option<ShutdownOutcome> {
+        self.state.shutdown
+    }
+
+    /// Releases the dynamic user Firecracker ran as, if it was started with [`MicroVm::spawn_as`]. This happens automatically when the
+    /// `MicroVm` is dropped, but errors are only printed then.
+    pub fn release_user(&mut self) -> Result<(), Error> {
+        match self.user.take() {
+            Some(user) => user.release(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::api::mock::MockServer;
+    use super::super::api::BootSource;
+    use super::*;
+
+    async fn attach_configuring(server: &MockServer) -> MicroVm<Configuring> {
+        match MicroVm::attach(server.client()).await.unwrap() {
+            AttachedVm::Configuring(vm) => vm,
+            vm => panic!("attached to {:?}", vm),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let server = MockServer::start_temp().unwrap();
+
+        let vm = attach_configuring(&server).await;
+        vm.configure(&VmConfig::new(BootSource {
+            kernel_image_path: "vmlinux.bin".into(),
+            initrd_path: None,
+            boot_args: None,
+        }))
+        .await
+        .unwrap();
+
+        let vm = vm.start().await.unwrap();
+        assert_eq!(vm.instance_state().await.unwrap(), InstanceState::Running);
+
+        let vm = vm.pause().await.unwrap();
+        assert_eq!(vm.instance_state().await.unwrap(), InstanceState::Paused);
+
+        let vm = vm.resume().await.unwrap();
+        // An attached microVM can't be killed, so stopping it hands it back
+        let vm = vm.stop().await.unwrap_err().vm;
+        assert_eq!(vm.instance_state().await.unwrap(), InstanceState::Running);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_attached() {
+        let server = MockServer::start_temp().unwrap();
+        server.state().instance_state = InstanceState::Running;
+        let policy = ShutdownPolicy {
+            grace_period: Duration::from_millis(200),
+            ..ShutdownPolicy::default()
+        };
+
+        // The mock keeps serving the API after Ctrl+Alt+Del, so the microVM is not known to have stopped
+        let vm = match MicroVm::attach(server.client()).await.unwrap() {
+            AttachedVm::Running(vm) => vm,
+            vm => panic!("attached to {:?}", vm),
+        };
+        let failure = vm.shutdown(&policy).await.unwrap_err();
+        assert!(matches!(failure.error, Error::Io { .. }));
+        assert_eq!(server.state().actions, vec![ActionType::SendCtrlAltDel]);
+
+        // Once the API goes away, it has
+        let policy = ShutdownPolicy {
+            grace_period: Duration::from_secs(5),
+            ..ShutdownPolicy::default()
+        };
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            drop(server);
+        });
+        let vm = failure.vm.shutdown(&policy).await.unwrap();
+        assert!(vm.exit_status().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_attach() {
+        let server = MockServer::start_temp().unwrap();
+
+        server.state().instance_state = InstanceState::Running;
+        let vm = match MicroVm::attach(server.client()).await.unwrap() {
+            AttachedVm::Running(vm) => vm,
+            vm => panic!("attached to {:?}", vm),
+        };
+        vm.pause().await.unwrap();
+        assert!(matches!(
+            MicroVm::attach(server.client()).await.unwrap(),
+            AttachedVm::Paused(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failed_start() {
+        let server = MockServer::start_temp().unwrap();
+
+        // Starting without a boot source fails, and hands back the microVM so it can be fixed
+        let vm = attach_configuring(&server).await;
+        let failure = vm.start().await.unwrap_err();
+        assert!(matches!(
+            failure.error,
+            Error::Api(super::super::api::Error::Client { .. })
+        ));
+        assert_eq!(
+            failure.vm.instance_state().await.unwrap(),
+            InstanceState::NotStarted
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Host-side connections to guest vsock ports
+//!
+//! Firecracker exposes the guest's vsock device as a Unix socket on the host. Connecting to a guest port is done by connecting to that socket
+//! and sending `CONNECT <port>\n`; Firecracker answers with `OK <host port>\n` and from then on forwards the connection to the guest. See the
+//! [vsock documentation](https://github.com/firecracker-microvm/firecracker/blob/main/docs/vsock.md).
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use super::api::Vsock;
+use super::jailer::Config;
+use crate::Error;
+
+/// Longest acknowledgement Firecracker sends: `OK ` followed by a 32-bit port number and a newline.
+const MAX_ACK_LENGTH: usize = 16;
+
+/// Connects to `port` inside the guest through the vsock device described by `vsock`, which must have been configured on the VM jailed by
+/// `config`. The returned stream is connected to the guest application listening on that port.
+pub async fn connect(config: &Config<'_>, vsock: &Vsock, port: u32) -> Result<UnixStream, Error> {
+    let socket_path = host_socket_path(&config.chroot_path(), &vsock.uds_path)?;
+
+    let mut stream = UnixStream::connect(&socket_path)
+        .await
+        .map_err(|error| Error::Io {
+            context: format!("could not connect to vsock at {}", socket_path.display()),
+            error,
+        })?;
+
+    handshake(&mut stream, port)
+        .await
+        .map_err(|error| Error::Io {
+            context: format!(
+                "vsock handshake for port {} on {} failed",
+                port,
+                socket_path.display()
+            ),
+            error,
+        })?;
+
+    Ok(stream)
+}
+
+/// Path on the host to a vsock socket that Firecracker created at `uds_path` inside its jail. Paths containing `..` are rejected, since
+/// they could refer to a socket outside the jail.
+fn host_socket_path(chroot_path: &Path, uds_path: &Path) -> Result<PathBuf, Error> {
+    // Firecracker runs with the jail as its root and working directory, so both absolute and relative paths are relative to the chroot
+    let mut relative = PathBuf::new();
+    for component in uds_path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(Error::InvalidJailPath(uds_path.to_path_buf()))
+            }
+        }
+    }
+    Ok(chroot_path.join(relative))
+}
+
+/// Performs the `CONNECT` handshake. The acknowledgement is read a byte at a time so that no data sent by the guest afterwards is consumed.
+async fn handshake(stream: &mut UnixStream, port: u32) -> io::Result<()> {
+    stream
+        .write_all(format!("CONNECT {}\n", port).as_bytes())
+        .await?;
+
+    let mut ack = Vec::with_capacity(MAX_ACK_LENGTH);
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        if ack.len() == MAX_ACK_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "vsock acknowledgement too long",
+            ));
+        }
+        ack.push(byte);
+    }
+
+    if ack.starts_with(b"OK ") {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!(
+                "unexpected vsock acknowledgement {:?}",
+                String::from_utf8_lossy(&ack)
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_socket_path() {
+        let chroot = Path::new("/srv/jailer/firecracker/vm/root");
+        assert_eq!(
+            host_socket_path(chroot, Path::new("/run/v.sock")).unwrap(),
+            Path::new("/srv/jailer/firecracker/vm/root/run/v.sock")
+        );
+        assert_eq!(
+            host_socket_path(chroot, Path::new("v.sock")).unwrap(),
+            Path::new("/srv/jailer/firecracker/vm/root/v.sock")
+        );
+        for escaping in &["../v.sock", "/run/../../v.sock"] {
+            assert!(matches!(
+                host_socket_path(chroot, Path::new(escaping)),
+                Err(Error::InvalidJailPath(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut host, mut vmm) = UnixStream::pair().unwrap();
+
+        let vmm = tokio::spawn(async move {
+            let mut request = [0u8; 11];
+            vmm.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"CONNECT 52\n");
+            vmm.write_all(b"OK 1073741824\nhello").await.unwrap();
+        });
+
+        handshake(&mut host, 52).await.unwrap();
+        vmm.await.unwrap();
+
+        // Data after the acknowledgement must be left for the caller
+        let mut data = String::new();
+        host.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        let (mut host, mut vmm) = UnixStream::pair().unwrap();
+        vmm.write_all(b"NOPE\n").await.unwrap();
+
+        let error = handshake(&mut host, 52).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
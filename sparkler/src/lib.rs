@@ -0,0 +1,8 @@
+//! Sparkler runs multi-machine development environments on top of [Firecracker](https://firecracker-microvm.github.io/) microVMs.
+
+pub mod error;
+pub mod firecracker;
+pub mod network;
+pub mod util;
+
+pub use error::Error;
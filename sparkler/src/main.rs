@@ -1,23 +1,28 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::{fs, time::Duration};
 
 use nix::unistd::{Gid, Uid};
 use tokio::task::spawn_blocking;
-use tokio::time;
+use tokio::{signal, time};
 use tracing::{error, info};
 
-mod error;
-mod firecracker;
-mod network;
-mod util;
-
-use error::Error;
-use firecracker::api::*;
-use firecracker::jailer::{self, ConfigBuilder};
+use sparkler::firecracker::api::*;
+use sparkler::firecracker::config::VmConfig;
+use sparkler::firecracker::gc::{self, GcOptions};
+use sparkler::firecracker::jailer::{self, ConfigBuilder};
+use sparkler::firecracker::shutdown::{shutdown, ShutdownPolicy};
+use sparkler::firecracker::staging::Stager;
+use sparkler::{network, Error};
 
 const NETWORK_NAMESPACE: &str = "test";
 
+/// How long to wait for Firecracker to start serving its API
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether the microVM has exited
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -29,16 +34,24 @@ async fn main() {
         Err(error) => die(&error),
     };
 
-    if let Err(error) = run(state.clone()).await {
+    let result = run(state.clone()).await;
+    if let Err(error) = &result {
         error!("run() failed: {}", error);
-        if let Err(cleanup_err) = spawn_blocking(|| cleanup_vm(state)).await.unwrap() {
-            error!("Cleanup failed: {}", cleanup_err);
-        }
-        die(&error);
     }
 
-    if let Err(error) = spawn_blocking(|| cleanup_vm(state)).await.unwrap() {
-        die(&error);
+    // Make sure Firecracker has exited before tearing down its environment
+    if let Err(error) = stop_vm(&state).await {
+        error!("Stopping Firecracker failed: {}", error);
+    }
+
+    let cleanup = spawn_blocking(|| cleanup_vm(state)).await.unwrap();
+    match (result, cleanup) {
+        (Err(error), Err(cleanup_err)) => {
+            error!("Cleanup failed: {}", cleanup_err);
+            die(&error);
+        }
+        (Err(error), _) | (_, Err(error)) => die(&error),
+        (Ok(()), Ok(())) => {}
     }
 }
 
@@ -53,12 +66,25 @@ fn die(error: &dyn std::error::Error) -> ! {
 
 #[derive(Debug)]
 struct VmState {
-    process: unshare::Child,
+    /// The jailer process, until it has been shut down
+    process: Option<unshare::Child>,
     chroot_path: PathBuf,
+    socket_path: PathBuf,
+    /// Configuration referring to the kernel and rootfs staged into the jail
+    vm_config: VmConfig,
 }
 
 #[tracing::instrument]
 fn setup_vm() -> Result<Arc<Mutex<VmState>>, Error> {
+    // Clean up after any previous run that crashed
+    let report = gc::collect(&GcOptions {
+        namespaces: true,
+        ..GcOptions::default()
+    })?;
+    if !report.jails.is_empty() || !report.namespaces.is_empty() {
+        info!("Cleaned up stale resources: {:?}", report);
+    }
+
     let network_namespace = network::namespace::create(NETWORK_NAMESPACE)?;
 
     let jailer_config = ConfigBuilder::default()
@@ -69,41 +95,40 @@ fn setup_vm() -> Result<Arc<Mutex<VmState>>, Error> {
         .build()
         .unwrap();
 
-    let image_path = jailer_config.chroot_path().join("image");
-    fs::create_dir_all(&image_path).map_err(|error| Error::Io {
-        context: format!("could not create image directory {}", image_path.display()),
-        error,
-    })?;
-
-    util::bind_mount("./image", &image_path)?;
+    let stager = Stager::new(&jailer_config);
+    let mut vm_config = VmConfig::new(stager.stage_boot_source(
+        Path::new("image/hello-vmlinux.bin"),
+        None,
+        Some("console=ttyS0 reboot=k panic=1 pci=off".into()),
+    )?);
+    vm_config.drives.push(stager.stage_drive(
+        "rootfs",
+        Path::new("image/hello-rootfs.ext4"),
+        true,
+        false,
+    )?);
 
     info!("Starting Firecracker");
     let process = jailer::spawn(&jailer_config)?;
 
     Ok(Arc::new(Mutex::new(VmState {
-        process,
+        process: Some(process),
         chroot_path: jailer_config.chroot_path(),
+        socket_path: jailer_config.api_socket_path(),
+        vm_config,
     })))
 }
 
 #[tracing::instrument]
 fn cleanup_vm(state: Arc<Mutex<VmState>>) -> Result<(), Error> {
-    use nix::mount::{umount2, MntFlags};
-
     info!("Cleaning up VMM");
     let state = state.lock().unwrap();
 
     network::namespace::delete(NETWORK_NAMESPACE)?;
 
-    let images_dir = state.chroot_path.join("image");
-    umount2(&images_dir, MntFlags::MNT_DETACH).map_err(|error| Error::System {
-        context: format!("could not unmount image directory {}", images_dir.display()),
-        error,
-    })?;
-
     // The chroot is in the "root" subdirectory of the VM's state path.
     let state_root = state.chroot_path.parent().unwrap();
-    fs::remove_dir_all(&state_root).map_err(|error| Error::Io {
+    fs::remove_dir_all(state_root).map_err(|error| Error::Io {
         context: format!("could not remove VM  state in {}", state_root.display()),
         error,
     })?;
@@ -112,68 +137,73 @@ fn cleanup_vm(state: Arc<Mutex<VmState>>) -> Result<(), Error> {
 }
 
 async fn run(state: Arc<Mutex<VmState>>) -> Result<(), Error> {
-    let socket_path = {
+    let (socket_path, vm_config) = {
         let state = state.lock().unwrap();
-        state.chroot_path.join("run").join("firecracker.socket")
+        (state.socket_path.clone(), state.vm_config.clone())
     };
 
-    let mut exists = false;
-    for _ in 0u8..10 {
-        if socket_path.exists() {
-            exists = true;
-            break;
-        }
-
-        time::sleep(Duration::from_secs(1)).await;
-    }
-
-    if !exists {
-        return Err(Error::Api(firecracker::api::Error::Server {
-            fault_message: "timed out waiting for socket to exist".into(),
-        }));
-    }
-
     info!(
         "Attempting to communicate with VMM at {}",
         socket_path.display()
     );
 
     let client = Client::new(socket_path);
+    if let Err(error) = client.wait_ready(STARTUP_TIMEOUT).await {
+        error!("Firecracker did not start: {}", error);
+        let exit_status = peek_exit_status(&state)?;
+        return Err(Error::StartupTimeout {
+            timeout: STARTUP_TIMEOUT,
+            exit_status,
+        });
+    }
 
-    client
-        .set_boot_source(&BootSource {
-            kernel_image_path: "image/hello-vmlinux.bin".into(),
-            initrd_path: None,
-            boot_args: Some("console=ttyS0 reboot=k panic=1 pci=off".into()),
-        })
-        .await?;
-
-    client
-        .set_drive(&Drive {
-            drive_id: "rootfs".into(),
-            is_read_only: false,
-            is_root_device: true,
-            path_on_host: "image/hello-rootfs.ext4".into(),
-            partuuid: None,
-            rate_limiter: None,
-        })
-        .await?;
+    vm_config.apply(&client).await?;
 
     client.action(ActionType::InstanceStart).await?;
 
     let info = client.instance_info().await?;
     info!("Instance info: {:?}", info);
 
-    let exit_status = spawn_blocking(move || {
-        let mut state = state.lock().unwrap();
-        state.process.wait().map_err(|error| Error::Io {
-            context: format!("waiting for jailer process {} failed", state.process.pid()),
-            error,
-        })
-    })
-    .await
-    .unwrap()?;
-    info!("Jailer complete: {}", exit_status);
+    // Run until the guest shuts down or we're interrupted
+    let exited = async {
+        loop {
+            if let Some(exit_status) = peek_exit_status(&state)? {
+                info!("Jailer complete: {}", exit_status);
+                return Ok::<_, Error>(());
+            }
+            time::sleep(EXIT_POLL_INTERVAL).await;
+        }
+    };
+    tokio::select! {
+        result = exited => result?,
+        _ = signal::ctrl_c() => info!("Interrupted, shutting down"),
+    }
 
     Ok(())
 }
+
+/// Checks whether the jailer has exited, without reaping it.
+fn peek_exit_status(state: &Mutex<VmState>) -> Result<Option<unshare::ExitStatus>, Error> {
+    match &state.lock().unwrap().process {
+        Some(process) => jailer::peek_exit_status(process),
+        None => Ok(None),
+    }
+}
+
+/// Shuts down Firecracker, if it's still running, and reaps the jailer process.
+async fn stop_vm(state: &Mutex<VmState>) -> Result<(), Error> {
+    let (process, socket_path) = {
+        let mut state = state.lock().unwrap();
+        (state.process.take(), state.socket_path.clone())
+    };
+    if let Some(mut process) = process {
+        let outcome = shutdown(
+            &Client::new(socket_path),
+            &mut process,
+            &ShutdownPolicy::default(),
+        )
+        .await?;
+        info!("Firecracker {}", outcome);
+    }
+    Ok(())
+}
@@ -1 +1 @@
-mod schema;
\ No newline at end of file
+pub mod schema;
@@ -12,10 +12,10 @@ use serde_json::Value;
 pub struct Versioned<T> {
     /// Semantic Version 2.0 of the CNI specification to which this object conforms.
     #[serde(rename = "cniVersion")]
-    cni_version: String,
+    pub cni_version: String,
 
     #[serde(flatten)]
-    payload: T,
+    pub payload: T,
 }
 
 /// CNI network configuration
@@ -26,10 +26,10 @@ pub struct NetworkConfiguration {
     /// Network name. This should be unique across all containers on the host (or other administrative domain).
     /// Must start with a alphanumeric character, optionally followed by any combination of one or more alphanumeric
     /// characters, underscore (_), dot (.) or hyphen (-).
-    name: String,
+    pub name: String,
 
     #[serde(flatten)]
-    plugin: PluginConfiguration,
+    pub plugin: PluginConfiguration,
 }
 
 /// CNI network configuration list.
@@ -40,16 +40,16 @@ pub struct NetworkConfigurationList {
     /// Network name. This should be unique across all containers on the host (or other administrative domain).
     /// Must start with a alphanumeric character, optionally followed by any combination of one or more alphanumeric
     /// characters, underscore (_), dot (.) or hyphen (-).
-    name: String,
+    pub name: String,
 
     /// f disableCheck is true, runtimes must not call CHECK for this network configuration list. This allows an administrator to prevent CHECKing where a combination of plugins is known to return spurious errors.
     #[serde(skip_serializing_if = "is_false")]
     #[serde(rename = "disableCheck")]
     #[serde(default)]
-    disable_check: bool,
+    pub disable_check: bool,
 
     /// A list of standard CNI network plugin configurations.
-    plugins: Vec<PluginConfiguration>,
+    pub plugins: Vec<PluginConfiguration>,
 }
 
 /// Configuration for a single CNI plugin. This may be included in either a single-plugin [`NetworkConfiguration`] or a multi-plugin
@@ -58,34 +58,34 @@ pub struct NetworkConfigurationList {
 pub struct PluginConfiguration {
     /// Refers to the filename of the CNI plugin executable.
     #[serde(rename = "type")]
-    plugin_type: String,
+    pub plugin_type: String,
 
     /// Additional arguments provided by the container runtime. For example a dictionary of labels could be passed to CNI
     /// plugins by adding them to a labels field under args.
     #[serde(default)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
-    args: HashMap<String, Value>,
+    pub args: HashMap<String, Value>,
 
     /// If supported by the plugin, sets up an IP masquerade on the host for this network.
     /// This is necessary if the host will act as a gateway to subnets that are not able to route to the IP assigned to the container.
     #[serde(rename = "ipMasq")]
     #[serde(default)]
     #[serde(skip_serializing_if = "is_false")]
-    ip_masq: bool,
+    pub ip_masq: bool,
 
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    ipam: Option<IpamConfiguration>,
+    pub ipam: Option<IpamConfiguration>,
 
     /// DNS-specific configuration
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    dns: Option<DnsConfiguration>,
+    pub dns: Option<DnsConfiguration>,
 
     /// Additional plugin-specific fields. Plugins may define additional fields that they accept and may generate an error if called with unknown fields.
     /// However, plugins should ignore fields in [`args`] if they are not understood.
     #[serde(flatten)]
-    other: HashMap<String, Value>,
+    pub other: HashMap<String, Value>,
 }
 
 /// IPAM (IP Address Management) plugin configuration.
@@ -93,11 +93,11 @@ pub struct PluginConfiguration {
 pub struct IpamConfiguration {
     /// Refers to the filename of the IPAM plugin executable.
     #[serde(rename = "type")]
-    plugin_type: String,
+    pub plugin_type: String,
 
     /// Additional plugin-specific fields. Plugins may define additional fields that they accept and may generate an error if called with unknown fields.
     #[serde(flatten)]
-    other: HashMap<String, Value>,
+    pub other: HashMap<String, Value>,
 }
 
 /// Common DNS information.
@@ -108,23 +108,23 @@ pub struct DnsConfiguration {
     /// A priority-ordered list of DNS nameservers that this network is aware of
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    nameservers: Vec<IpAddr>,
+    pub nameservers: Vec<IpAddr>,
 
     /// The local domain used for short hostname lookups
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    domain: Option<String>,
+    pub domain: Option<String>,
 
     /// List of priority-ordered search domains for short hostname lookups. Will be preferred over [`domain`]
     /// by most resolvers.
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    search: Vec<String>,
+    pub search: Vec<String>,
 
     /// List of options that can be passed to the resolver.
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    options: Vec<String>,
+    pub options: Vec<String>,
 }
 
 /// Result of a CNI plugin invocation.
@@ -135,32 +135,32 @@ pub struct PluginResult {
     /// Specific network interfaces the plugin created. If the `CNI_IFNAME` variable exists the plugin must use that name for the sandbox/hypervisor
     /// interface or return an error if it cannot.
     #[serde(default)]
-    interfaces: Vec<Interface>,
+    pub interfaces: Vec<Interface>,
 
     #[serde(default)]
-    ips: Vec<IpConfiguration>,
+    pub ips: Vec<IpConfiguration>,
 
     #[serde(default)]
-    routes: Vec<RouteConfiguration>,
+    pub routes: Vec<RouteConfiguration>,
 
     #[serde(default)]
-    dns: Option<DnsConfiguration>,
+    pub dns: Option<DnsConfiguration>,
 }
 
 /// A network interface created by a CNI plugin.
 #[derive(Debug, Deserialize)]
 pub struct Interface {
     /// Network interface name.
-    name: String,
+    pub name: String,
 
     /// The hardware address of the interface. If L2 addresses are not meaningful for the plugin then this field is optional.
     #[serde(default)]
-    mac: Option<String>,
+    pub mac: Option<String>,
 
     /// Container/namespace-based environments should return the full filesystem path to the network namespace of that sandbox.
     /// Hypervisor/VM-based plugins should return an ID unique to the virtualized sandbox the interface was created in. This
     /// item must be provided for interfaces created or moved into a sandbox like a network namespace or a hypervisor/VM.
-    sandbox: String,
+    pub sandbox: String,
 }
 
 /// IP configuration information provided by a CNI plugin.
@@ -169,17 +169,17 @@ pub struct Interface {
 #[derive(Debug, Deserialize)]
 pub struct IpConfiguration {
     /// IP address range in CIDR notation
-    address: String,
+    pub address: String,
 
     /// The default gateway for this subnet, if one exists. It does not instruct the CNI plugin to add any routes with this gateway:
     /// routes to add are specified separately via the routes field. An example use of this value is for the CNI bridge plugin to add
     /// this IP address to the Linux bridge to make it a gateway.
     #[serde(default)]
-    gateway: Option<String>,
+    pub gateway: Option<String>,
 
     /// Index into the [`Result::interfaces`] list of a CNI plugin result indicating which interface this IP configuration should be applied
     /// to.
-    interface: usize,
+    pub interface: usize,
 }
 
 /// IP routing configuration. Each `RouteConfiguration` must be relevant to the sandbox interface specified by `CNI_IFNAME`.
@@ -189,12 +189,12 @@ pub struct IpConfiguration {
 pub struct RouteConfiguration {
     /// Destination subnet specified in CIDR notation.
     #[serde(rename = "dst")]
-    destination: String,
+    pub destination: String,
 
     /// IP of the gateway. If omitted, a default gateway is assumed (as determined by the CNI plugin).
     #[serde(rename = "gw")]
     #[serde(default)]
-    gateway: Option<String>,
+    pub gateway: Option<String>,
 }
 
 /// Abbreviated form of [`Result`] returned by IPAM plugins.
@@ -203,39 +203,39 @@ pub struct RouteConfiguration {
 #[derive(Debug, Deserialize)]
 pub struct IpamResult {
     /// IP configuration
-    ips: Vec<IpamIpConfiguration>,
+    pub ips: Vec<IpamIpConfiguration>,
 
     /// Route configuration.
     #[serde(default)]
-    routes: Vec<RouteConfiguration>,
+    pub routes: Vec<RouteConfiguration>,
 
     /// Common DNS information.
-    dns: Option<DnsConfiguration>,
+    pub dns: Option<DnsConfiguration>,
 }
 
 /// Version of [`IpConfiguration`] that omits fields that should not be returned by IPAM plugins.
 #[derive(Debug, Deserialize)]
 pub struct IpamIpConfiguration {
     /// IP address range in CIDR notation
-    address: String,
+    pub address: String,
 
     /// The default gateway for this subnet, if one exists. It does not instruct the CNI plugin to add any routes with this gateway:
     /// routes to add are specified separately via the routes field. An example use of this value is for the CNI bridge plugin to add
     /// this IP address to the Linux bridge to make it a gateway.
     #[serde(default)]
-    gateway: Option<String>,
+    pub gateway: Option<String>,
 }
 
 /// A CNI plugin error. Note that plugins may also log unstructured information to stderr.
 #[derive(Debug, Deserialize)]
 pub struct Error {
-    code: ErrorCode,
+    pub code: ErrorCode,
 
     #[serde(rename = "msg")]
-    message: String,
+    pub message: String,
 
     #[serde(default)]
-    details: Option<String>,
+    pub details: Option<String>,
 }
 
 /// A CNI error code. See the [Well-known Error Codes](https://github.com/containernetworking/cni/blob/master/SPEC.md#well-known-error-codes).
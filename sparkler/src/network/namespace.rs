@@ -1,9 +1,10 @@
 //! Utilities for dealing with Linux network namespaces
 
 use std::fs::{self, DirBuilder, File, OpenOptions};
-use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 use nix::{
     errno::Errno,
@@ -25,6 +26,10 @@ use crate::Error;
 /// Persistent network namespaces are (at least by convention) bound to files under /var/run/netns.
 const NETNS_RUNTIME_DIRECTORY: &str = "/var/run/netns";
 
+/// Namespaces created by sparkler are recorded by creating a file with the same name here, so that cleaning up never touches namespaces
+/// created by anything else.
+const OWNED_NAMESPACE_DIRECTORY: &str = "/run/sparkler/netns";
+
 /// For use with `mount`, to provide type annotations for `None`
 const NONE: Option<&'static [u8]> = None;
 
@@ -32,7 +37,7 @@ const NONE: Option<&'static [u8]> = None;
 // panics because we cannot meaningfully recover from being in the wrong network namespace.
 struct NamespaceGuard(File);
 
-/// Create a persistent network namespace named `name`.
+/// Create a persistent network namespace named `name`, recording that sparkler owns it (see [`list_owned`]).
 pub fn create(name: &str) -> Result<PathBuf, Error> {
     prepare_runtime_directory()?;
 
@@ -75,6 +80,21 @@ pub fn create(name: &str) -> Result<PathBuf, Error> {
         return Err(error);
     }
 
+    // Step 3: Record that we own the namespace
+    let marker_path = owned_marker_path(name);
+    if let Err(error) = fs::create_dir_all(OWNED_NAMESPACE_DIRECTORY)
+        .and_then(|()| File::create(&marker_path).map(drop))
+    {
+        let _ = delete(name);
+        return Err(Error::Io {
+            context: format!(
+                "could not record network namespace in {}",
+                marker_path.display()
+            ),
+            error,
+        });
+    }
+
     Ok(namespace_path)
 }
 
@@ -86,7 +106,94 @@ pub fn delete(name: &str) -> Result<(), Error> {
     fs::remove_file(&path).map_err(|error| Error::Io {
         context: format!("could not remove namespace file {}", path.display()),
         error,
-    })
+    })?;
+
+    let marker_path = owned_marker_path(name);
+    match fs::remove_file(&marker_path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(Error::Io {
+            context: format!("could not remove {}", marker_path.display()),
+            error,
+        }),
+    }
+}
+
+/// List the names of persistent network namespaces.
+pub fn list() -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(NETNS_RUNTIME_DIRECTORY) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => {
+            return Err(Error::Io {
+                context: format!("could not list {}", NETNS_RUNTIME_DIRECTORY),
+                error,
+            })
+        }
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|error| Error::Io {
+            context: format!("could not list {}", NETNS_RUNTIME_DIRECTORY),
+            error,
+        })?;
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// List the names of persistent network namespaces that were created by sparkler with [`create`] and have not been deleted.
+pub fn list_owned() -> Result<Vec<String>, Error> {
+    Ok(list()?
+        .into_iter()
+        .filter(|name| owned_marker_path(name).exists())
+        .collect())
+}
+
+/// When sparkler created the persistent network namespace `name`, which must be [owned](list_owned).
+pub fn created(name: &str) -> Result<SystemTime, Error> {
+    let marker_path = owned_marker_path(name);
+    fs::metadata(&marker_path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|error| Error::Io {
+            context: format!("could not inspect {}", marker_path.display()),
+            error,
+        })
+}
+
+/// Check whether any process is running in the persistent network namespace `name`.
+pub fn in_use(name: &str) -> Result<bool, Error> {
+    let path = persistent_namespace_path(name);
+    let namespace = fs::metadata(&path).map_err(|error| Error::Io {
+        context: format!("could not inspect namespace file {}", path.display()),
+        error,
+    })?;
+
+    let processes = fs::read_dir("/proc").map_err(|error| Error::Io {
+        context: "could not list processes".into(),
+        error,
+    })?;
+    for entry in processes.flatten() {
+        let is_pid = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+        // Processes may exit while we're looking, or belong to other users
+        if let Ok(process_namespace) = fs::metadata(entry.path().join("ns/net")) {
+            if process_namespace.dev() == namespace.dev()
+                && process_namespace.ino() == namespace.ino()
+            {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
 }
 
 /// Prepare the root runtime directory for persistent network namespaces.
@@ -176,6 +283,13 @@ fn persistent_namespace_path(name: &str) -> PathBuf {
     path
 }
 
+/// Gets the path of the file recording that sparkler created the namespace `name`.
+fn owned_marker_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(OWNED_NAMESPACE_DIRECTORY);
+    path.push(name);
+    path
+}
+
 impl NamespaceGuard {
     /// Create a new [`NamespaceGuard`] that will restore the current network namespace of the process. This allows temporarily switching to another network
     /// namespace with [`sched::unshare`].
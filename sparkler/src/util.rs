@@ -1,7 +1,11 @@
 //! Miscellaneous utilities
 
 use std::os::unix::io::AsRawFd;
-use std::{fmt::Debug, fs::File};
+use std::path::Path;
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+};
 
 use nix::{
     fcntl,
@@ -23,7 +27,7 @@ pub struct FileLock<'a>(&'a File);
 
 impl<'a> FileLock<'a> {
     /// Take an exclusive lock on `file`, returning a `FileLock` guard.
-    pub fn new(file: &'a File) -> Result<FileLock, nix::Error> {
+    pub fn new(file: &'a File) -> Result<FileLock<'a>, nix::Error> {
         fcntl::flock(file.as_raw_fd(), fcntl::FlockArg::LockExclusive)?;
         Ok(FileLock(file))
     }
@@ -58,3 +62,117 @@ pub fn bind_mount_flags<P1: ?Sized + nix::NixPath + Debug, P2: ?Sized + nix::Nix
         }
     })
 }
+
+/// Hard-link `source` to `target`, falling back to [`reflink_or_copy`] if they are on different filesystems or the kernel refuses to link
+/// a file we don't own (see `fs.protected_hardlinks`). Returns whether `target` is a hard link, which shares its inode, and so its owner and
+/// permissions, with `source`.
+pub fn link_or_copy(source: &Path, target: &Path) -> Result<bool, Error> {
+    match fs::hard_link(source, target) {
+        Ok(()) => Ok(true),
+        Err(err)
+            if err.raw_os_error() == Some(nix::libc::EXDEV)
+                || err.raw_os_error() == Some(nix::libc::EPERM) =>
+        {
+            reflink_or_copy(source, target).map(|()| false)
+        }
+        Err(error) => Err(Error::Io {
+            context: format!(
+                "could not link {} to {}",
+                source.display(),
+                target.display()
+            ),
+            error,
+        }),
+    }
+}
+
+mod ioctl {
+    // FICLONE from linux/fs.h
+    nix::ioctl_write_int!(ficlone, 0x94, 9);
+}
+
+/// Create `target` as a copy-on-write clone of `source`, with the same permissions. This only works within a filesystem that supports
+/// reflinks, such as Btrfs or XFS. If cloning fails, `target` is removed.
+///
+/// See the `ioctl_ficlone(2)` man page.
+pub fn reflink(source: &Path, target: &Path) -> Result<(), Error> {
+    let io_error = |error| Error::Io {
+        context: format!(
+            "could not clone {} to {}",
+            source.display(),
+            target.display()
+        ),
+        error,
+    };
+
+    let source_file = File::open(source).map_err(io_error)?;
+    let permissions = source_file.metadata().map_err(io_error)?.permissions();
+    let target_file = File::create(target).map_err(io_error)?;
+    let result = unsafe { ioctl::ficlone(target_file.as_raw_fd(), source_file.as_raw_fd() as _) }
+        .map_err(|error| Error::System {
+            context: format!(
+                "could not clone {} to {}",
+                source.display(),
+                target.display()
+            ),
+            error,
+        })
+        .and_then(|_| target_file.set_permissions(permissions).map_err(io_error));
+
+    if result.is_err() {
+        let _ = fs::remove_file(target);
+    }
+    result
+}
+
+/// Create `target` as a private copy of `source`, which can be written or chowned without affecting `source`. A reflink is used if the
+/// filesystem supports it, and otherwise the contents are copied.
+pub fn reflink_or_copy(source: &Path, target: &Path) -> Result<(), Error> {
+    if reflink(source, target).is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, target)
+        .map(|_| ())
+        .map_err(|error| Error::Io {
+            context: format!(
+                "could not copy {} to {}",
+                source.display(),
+                target.display()
+            ),
+            error,
+        })
+}
+
+/// A scratch directory for tests, removed with everything in it when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates an empty directory whose name includes `name`, unique within this test run.
+    pub(crate) fn new(name: &str) -> TempDir {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "sparkler-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("could not create test directory");
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}